@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+use crate::command::Command;
+
+// sets and removes applied by KvStore::write as a single unit.
+// only the last operation on each key is kept.
+#[derive(Clone,PartialEq,Debug,Default)]
+pub struct WriteBatch {
+    ops: BTreeMap<Vec<u8>,Option<Vec<u8>>>, // key -> new value, or None to remove it
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut WriteBatch {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> &mut WriteBatch {
+        self.ops.insert(key.to_vec(), Some(value.to_vec()));
+        self
+    }
+
+    // unlike KvStore::remove, removing a key that doesn't exist isn't an error
+    pub fn remove(&mut self, key: String) -> &mut WriteBatch {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> &mut WriteBatch {
+        self.ops.insert(key.to_vec(), None);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    // Some(None) if the batch removes the key
+    pub(crate) fn op(&self, key: &[u8]) -> Option<&Option<Vec<u8>>> {
+        self.ops.get(key)
+    }
+
+    // the batch logged by into_command
+    pub(crate) fn from_command(command: Command) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for (op, _) in command.ops(0) {
+            match op {
+                Command::Set { key, value, expires: _ } => batch.set_bytes(&key, &value),
+                Command::Remove { key } => batch.remove_bytes(&key),
+                _ => continue,
+            };
+        }
+        batch
+    }
+
+    pub(crate) fn into_command(self) -> Command {
+        let ops = self.ops.into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Command::Set { key, value, expires: None },
+                None => Command::Remove { key },
+            })
+            .collect();
+        Command::Batch { ops }
+    }
+}
@@ -111,7 +111,7 @@ fn run(matches: ArgMatches) -> kvs::Result<()> {
 }
 
 pub fn get(path: &str, key: &str) -> kvs::Result<Option<String>> {
-    let mut store = kvs::KvStore::open(&PathBuf::from(&path))?;
+    let store = kvs::KvStore::open(&PathBuf::from(&path))?;
 
     let value = store.get(key.to_owned())?;
 
@@ -0,0 +1,95 @@
+use std::fs::{self,OpenOptions};
+use std::io::{self,BufReader,Write};
+use std::path::{Path,PathBuf};
+
+use crate::result::*;
+use crate::command::Command;
+use crate::frames;
+use crate::globber::Globber;
+
+pub type BlobId = u64;
+
+// large values, each kept in a file of its own next to the partitions, with the log holding only
+// a reference to it. compaction copies the reference and leaves the blob alone.
+#[derive(Clone,Debug)]
+pub struct Blobs {
+    pub dir: PathBuf,
+    pub globber: Globber,
+}
+
+impl Blobs {
+    pub fn new(dir: &Path) -> Blobs {
+        let pattern = dir.join("*.blob");
+        Blobs {
+            dir: dir.to_owned(),
+            globber: Globber { pattern: pattern.to_str().unwrap().to_owned() },
+        }
+    }
+
+    pub fn path_for_id(&self, id: BlobId) -> PathBuf {
+        self.dir.join(format!("{}.blob", id))
+    }
+
+    pub fn find(&self) -> Result<Vec<BlobId>> {
+        let mut result = vec![];
+        for path in self.globber.find()? {
+            let name = path.file_stem()
+                .ok_or_else(|| KvsErrorKind::GlobError(format!("error parsing file name {:?}", path.to_str())))?;
+            let id = name.to_string_lossy().parse::<BlobId>()
+                .map_err(KvsErrorKind::ParseIntError)?;
+            result.push(id);
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    pub fn next_id(&self) -> Result<BlobId> {
+        Ok(self.find()?.last().map_or(1, |id| id + 1))
+    }
+
+    // the value is framed like a log record, so damage to it is noticed when it's read back
+    pub fn write(&self, id: BlobId, value: &[u8], sync: bool) -> Result<()> {
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.path_for_id(id))
+            .map_err(KvsErrorKind::Io)?;
+        frames::write_frame(&mut f, value)
+            .and_then(|_| f.flush())
+            .and_then(|_| if sync { f.sync_all() } else { Ok(()) })
+            .map_err(KvsErrorKind::Io)?;
+        Ok(())
+    }
+
+    pub fn read(&self, id: BlobId) -> Result<Vec<u8>> {
+        let f = match fs::File::open(self.path_for_id(id)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(KvsErrorKind::NotFound(format!("blob {}", id)))?,
+            Err(e) => Err(KvsErrorKind::Io(e))?,
+        };
+        let value = frames::read_frame(&mut BufReader::new(f))
+            .map_err(KvsErrorKind::Io)?
+            .ok_or_else(|| KvsErrorKind::Codec(format!("empty blob {}", id)))?;
+        Ok(value)
+    }
+
+    pub fn remove(&self, id: BlobId) -> Result<()> {
+        fs::remove_file(self.path_for_id(id))
+            .map_err(KvsErrorKind::Io)?;
+        Ok(())
+    }
+
+    // turns references to blobs back into the sets they stand for
+    pub fn resolve(&self, command: Command) -> Result<Command> {
+        match command {
+            Command::Blob { key, id, expires } => Ok(Command::Set { key, value: self.read(id)?, expires }),
+            Command::Batch { ops } => {
+                let ops = ops.into_iter()
+                    .map(|op| self.resolve(op))
+                    .collect::<Result<Vec<Command>>>()?;
+                Ok(Command::Batch { ops })
+            },
+            command => Ok(command),
+        }
+    }
+}
@@ -0,0 +1,16 @@
+// outcome of a conditional write
+#[derive(Clone,PartialEq,Debug)]
+pub enum CasResult<V> {
+    Written,
+    // the key didn't hold the expected value. holds what it does hold, None if it's missing
+    Mismatch(Option<V>),
+}
+
+impl <V> CasResult<V> {
+    pub fn is_written(&self) -> bool {
+        match self {
+            CasResult::Written => true,
+            CasResult::Mismatch(_) => false,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::result::*;
+use crate::command::*;
+
+// encodes commands to and from the payload of a log record
+pub trait Codec: Debug + Send + Sync {
+    // recorded in the store directory so the store is always reopened with the same codec
+    fn name(&self) -> &str;
+    fn encode(&self, command: &Command) -> Result<Vec<u8>>;
+    fn decode(&self, record: &[u8]) -> Result<Command>;
+}
+
+// stores opened before codecs were configurable have no format marker and are json
+pub const DEFAULT_CODEC: &str = "json";
+
+pub fn builtin(name: &str) -> Option<Arc<dyn Codec>> {
+    match name {
+        "json" => Some(Arc::new(JsonCodec)),
+        "binary" => Some(Arc::new(BinaryCodec)),
+        _ => None,
+    }
+}
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn encode(&self, command: &Command) -> Result<Vec<u8>> {
+        let s = serde_json::to_vec(command)
+            .map_err(KvsErrorKind::ParserError)?;
+        Ok(s)
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<Command> {
+        let command = serde_json::from_slice(record)
+            .map_err(KvsErrorKind::ParserError)?;
+        Ok(command)
+    }
+}
+
+const OP_SET: u8 = 0;
+const OP_REMOVE: u8 = 1;
+const OP_BATCH: u8 = 2;
+const OP_SET_EXPIRING: u8 = 3;
+const OP_MERGE: u8 = 4;
+const OP_BLOB: u8 = 5;
+const OP_BLOB_EXPIRING: u8 = 6;
+
+// compact encoding: op tag byte, then each field as a u32 le length followed by its bytes.
+// a batch is its op count followed by each of its ops encoded the same way.
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn name(&self) -> &str {
+        "binary"
+    }
+
+    fn encode(&self, command: &Command) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        match command {
+            Command::Set { key, value, expires: None } => {
+                buf.push(OP_SET);
+                put_bytes(&mut buf, key);
+                put_bytes(&mut buf, value);
+            },
+            Command::Set { key, value, expires: Some(expires) } => {
+                buf.push(OP_SET_EXPIRING);
+                put_bytes(&mut buf, key);
+                put_bytes(&mut buf, value);
+                buf.extend_from_slice(&expires.to_le_bytes());
+            },
+            Command::Remove { key } => {
+                buf.push(OP_REMOVE);
+                put_bytes(&mut buf, key);
+            },
+            Command::Merge { key, operand } => {
+                buf.push(OP_MERGE);
+                put_bytes(&mut buf, key);
+                put_bytes(&mut buf, operand);
+            },
+            Command::Blob { key, id, expires: None } => {
+                buf.push(OP_BLOB);
+                put_bytes(&mut buf, key);
+                buf.extend_from_slice(&id.to_le_bytes());
+            },
+            Command::Blob { key, id, expires: Some(expires) } => {
+                buf.push(OP_BLOB_EXPIRING);
+                put_bytes(&mut buf, key);
+                buf.extend_from_slice(&id.to_le_bytes());
+                buf.extend_from_slice(&expires.to_le_bytes());
+            },
+            Command::Batch { ops } => {
+                buf.push(OP_BATCH);
+                buf.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+                for op in ops {
+                    if let Command::Batch { ops: _ } = op {
+                        Err(KvsErrorKind::Codec("nested batch".to_owned()))?;
+                    }
+                    put_bytes(&mut buf, &self.encode(op)?);
+                }
+            },
+        }
+        Ok(buf)
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<Command> {
+        let mut r = Reader { buf: record, pos: 0 };
+        let command = match r.u8()? {
+            OP_SET => Command::Set { key: r.bytes()?.to_vec(), value: r.bytes()?.to_vec(), expires: None },
+            OP_SET_EXPIRING => Command::Set { key: r.bytes()?.to_vec(), value: r.bytes()?.to_vec(), expires: Some(r.u64()?) },
+            OP_REMOVE => Command::Remove { key: r.bytes()?.to_vec() },
+            OP_MERGE => Command::Merge { key: r.bytes()?.to_vec(), operand: r.bytes()?.to_vec() },
+            OP_BLOB => Command::Blob { key: r.bytes()?.to_vec(), id: r.u64()?, expires: None },
+            OP_BLOB_EXPIRING => Command::Blob { key: r.bytes()?.to_vec(), id: r.u64()?, expires: Some(r.u64()?) },
+            OP_BATCH => {
+                let n = r.u32()?;
+                let mut ops = vec![];
+                for _ in 0..n {
+                    ops.push(self.decode(r.bytes()?)?);
+                }
+                Command::Batch { ops }
+            },
+            op => Err(KvsErrorKind::Codec(format!("unknown op {}", op)))?,
+        };
+        if r.pos != record.len() {
+            Err(KvsErrorKind::Codec(format!("{} trailing bytes", record.len() - r.pos)))?;
+        }
+        Ok(command)
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl <'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            Err(KvsErrorKind::Codec(format!("record truncated at {}", self.pos)))?;
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut n = [0u8; 4];
+        n.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(n))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut n = [0u8; 8];
+        n.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(n))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
@@ -1,14 +1,134 @@
-
 use serde::{Serialize, Deserialize};
+use std::time::{SystemTime,UNIX_EPOCH};
 
-#[derive(Serialize,Deserialize)]
+#[derive(Clone,PartialEq,Debug,Serialize,Deserialize)]
 #[serde(tag = "op")]
 pub enum Command {
     Set {
-        key: String,
-        value: String,
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        value: Vec<u8>,
+        // when the key expires, in milliseconds since the unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
     },
     Remove {
-        key: String,
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+    },
+    // an operand for the store's merge operator, folded into the key's value when it's read
+    Merge {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        #[serde(with = "bytes")]
+        operand: Vec<u8>,
+    },
+    // a set whose value is kept in a blob file of its own
+    Blob {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
+    // sets and removes written as a single record, so they're applied all together or not at all
+    Batch {
+        ops: Vec<Command>,
+    },
+}
+
+impl Command {
+    // the sets and removes making up a record, each with its share of the record's length on disk.
+    // the shares of a batch add up to the length of the whole record.
+    pub fn ops(self, len: u64) -> Vec<(Command,u64)> {
+        match self {
+            Command::Batch { ops } => {
+                let n = ops.len().max(1) as u64;
+                ops.into_iter()
+                    .enumerate()
+                    .map(|(i, op)| (op, if i == 0 { len / n + len % n } else { len / n }))
+                    .collect()
+            },
+            command => vec![(command, len)],
+        }
+    }
+
+    // the last operation on key in the record
+    pub fn find(self, key: &[u8]) -> Option<Command> {
+        self.ops(0)
+            .into_iter()
+            .map(|(op, _)| op)
+            .rfind(|op| op.key() == Some(key))
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            Command::Set { key, value: _, expires: _ } => Some(key),
+            Command::Remove { key } => Some(key),
+            Command::Merge { key, operand: _ } => Some(key),
+            Command::Blob { key, id: _, expires: _ } => Some(key),
+            Command::Batch { ops: _ } => None,
+        }
+    }
+}
+
+// milliseconds since the unix epoch, as used for expiry times
+pub fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs().saturating_mul(1000).saturating_add(d.subsec_millis() as u64))
+        .unwrap_or(0)
+}
+
+pub fn now() -> u64 {
+    timestamp(SystemTime::now())
+}
+
+pub fn is_expired(expires: Option<u64>, now: u64) -> bool {
+    matches!(expires, Some(t) if t <= now)
+}
+
+// keys and values are written as json strings when they're valid utf-8, which keeps
+// existing stores readable, and as arrays of byte values otherwise
+mod bytes {
+    use std::fmt;
+    use serde::{Serializer,Deserializer};
+    use serde::de::{self,SeqAccess};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => s.serialize_str(text),
+            Err(_) => s.serialize_bytes(bytes),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        d.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl <'de> de::Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+            Ok(v.as_bytes().to_vec())
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(b) = seq.next_element::<u8>()? {
+                bytes.push(b);
+            }
+            Ok(bytes)
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,310 @@
+use std::collections::{BTreeMap,BTreeSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::sync::mpsc::{self,Receiver,TryRecvError};
+use std::thread::{self,JoinHandle};
+
+use crate::result::*;
+use crate::command::{self,Command};
+use crate::blob::Blobs;
+use crate::codec::Codec;
+use crate::merge::{self,MergeOperator};
+use crate::kvdb::{KvDb,Visitor};
+use crate::logdb::Offset;
+use crate::parts::{Parts,Id};
+use crate::{KvStore,OffsetIndex,Entry,PartStats};
+
+// everything the compaction thread needs, so it never touches the store itself
+pub struct CompactionJob {
+    pub parts: Parts,
+    pub codec: Arc<dyn Codec>,
+    pub candidates: Vec<(Id,PartStats)>, // sealed partitions that may be rewritten, in replay order
+    pub index: Arc<OffsetIndex>, // index as of the start of the compaction
+    pub garbage_ratio: f64, // partitions with at least this share of dead bytes are rewritten
+    pub now: u64, // keys that expired before this are dropped
+    pub merge_operator: Option<Arc<dyn MergeOperator>>, // merges are collapsed into sets if there is one
+    pub cancel: Arc<AtomicBool>,
+}
+
+// a run of adjacent partitions in the replay order, rewritten into a single new partition
+pub struct CompactedRun {
+    pub sources: Vec<Id>,
+    pub dest_part: Id,
+    pub dest: KvDb,
+    pub moved: Vec<(Vec<u8>,Entry,Entry)>, // key, where it was copied from, where it was copied to
+    pub expired: Vec<(Vec<u8>,Entry)>, // keys whose values had expired and weren't copied, and where they were
+    pub collapsed: Vec<(Vec<u8>,Vec<Entry>,Entry)>, // key, the records that were merged, the set they were merged into
+    pub entries: u64, // number of operations read from the sources
+    pub records: u64, // number of commands copied to the destination
+}
+
+pub struct CompactionOutput {
+    pub runs: Vec<CompactedRun>,
+}
+
+// a compaction running on its own thread
+pub struct Compaction {
+    cancel: Arc<AtomicBool>,
+    result: Receiver<Result<CompactionOutput>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compaction {
+    pub fn start(job: CompactionJob) -> Compaction {
+        let cancel = job.cancel.clone();
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let _ = tx.send(job.run());
+        });
+
+        Compaction {
+            cancel,
+            result: rx,
+            handle: Some(handle),
+        }
+    }
+
+    // the output, if the compaction has finished
+    pub fn poll(&mut self) -> Option<Result<CompactionOutput>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(self.finish(result)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(self.finish(Err(KvsErrorKind::Compaction("compaction thread exited".to_owned()).into()))),
+        }
+    }
+
+    pub fn wait(&mut self) -> Result<CompactionOutput> {
+        let result = self.result.recv()
+            .unwrap_or_else(|_| Err(KvsErrorKind::Compaction("compaction thread exited".to_owned()).into()));
+        self.finish(result)
+    }
+
+    pub fn cancel(&mut self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    fn finish(&mut self, result: Result<CompactionOutput>) -> Result<CompactionOutput> {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        result
+    }
+}
+
+impl CompactionJob {
+    pub fn run(self) -> Result<CompactionOutput> {
+        let parts = self.parts.clone();
+        let mut runs = vec![];
+        match self.compact(&mut runs) {
+            Ok(()) => Ok(CompactionOutput { runs }),
+            Err(e) => {
+                for run in runs {
+                    let _ = parts.remove(run.dest_part);
+                }
+                Err(e)
+            },
+        }
+    }
+
+    fn compact(&self, runs: &mut Vec<CompactedRun>) -> Result<()> {
+        for (start, sources) in self.select()? {
+            // with nothing older left to shadow, tombstones can go too
+            let drop_tombstones = start == 0;
+            runs.push(self.copy(sources, drop_tombstones)?);
+        }
+
+        Ok(())
+    }
+
+    // picks the partitions worth rewriting, grouped into runs of neighbours in the replay order.
+    // besides those that are mostly garbage, that's any with merges that can be collapsed.
+    // returns each run with the position of its first partition among the candidates.
+    fn select(&self) -> Result<Vec<(usize,Vec<Id>)>> {
+        let mut runs: Vec<(usize,Vec<Id>)> = vec![];
+        let mut prev = None;
+
+        let collapsible: BTreeSet<Id> = match self.merge_operator {
+            Some(_) => self.index.values().filter(|e| !e.older.is_empty()).map(|e| e.part).collect(),
+            None => BTreeSet::new(),
+        };
+
+        for (i, (id, stats)) in self.candidates.iter().enumerate() {
+            if stats.total_bytes == 0 || (stats.dead_ratio() < self.garbage_ratio && !collapsible.contains(id)) {
+                continue;
+            }
+
+            match runs.last_mut() {
+                Some((_, ref mut run)) if prev.map(|p| p + 1) == Some(i) => run.push(*id),
+                _ => runs.push((i, vec![*id])),
+            }
+            prev = Some(i);
+        }
+
+        Ok(runs)
+    }
+
+    fn copy(&self, sources: Vec<Id>, drop_tombstones: bool) -> Result<CompactedRun> {
+        let (dest_part, file) = self.parts.create()?;
+        let mut run = CompactedRun {
+            sources,
+            dest_part,
+            dest: KvDb::new(dest_part, file, self.codec.clone())?,
+            moved: vec![],
+            expired: vec![],
+            collapsed: vec![],
+            entries: 0,
+            records: 0,
+        };
+
+        match self.fill(&mut run, drop_tombstones) {
+            Ok(()) => Ok(run),
+            Err(e) => {
+                let _ = self.parts.remove(dest_part);
+                Err(e)
+            },
+        }
+    }
+
+    fn fill(&self, run: &mut CompactedRun, drop_tombstones: bool) -> Result<()> {
+        let mut readers = BTreeMap::new();
+        for id in run.sources.clone() {
+            let src = KvDb::open(&self.parts, id, self.codec.clone())?;
+            let copy_visitor = CopyVisitor {
+                job: self,
+                src_part: id,
+                drop_tombstones,
+                run,
+                readers: &mut readers,
+            };
+            src.visit(copy_visitor)?;
+
+            self.check_cancelled()?;
+        }
+
+        run.dest.sync()?;
+        KvStore::build_hint(&self.parts, run.dest_part, &run.dest)
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.cancel.load(Ordering::SeqCst) {
+            Err(KvsErrorKind::Cancelled)?;
+        }
+        Ok(())
+    }
+}
+
+// where a record sits among the records making up its key's value
+#[derive(Copy,Clone,PartialEq,Debug)]
+enum Place {
+    Latest, // the record the index points at
+    Older, // an earlier record that merges since are applied to
+    Replaced, // not part of the value any more, or the key is missing
+}
+
+fn place(entry: Option<&Entry>, part: Id, pos: Offset) -> Place {
+    let at = Entry::new(part, pos, 0);
+    match entry {
+        Some(e) if e.is_at(&at) => Place::Latest,
+        Some(e) if e.older.iter().any(|o| o.is_at(&at)) => Place::Older,
+        _ => Place::Replaced,
+    }
+}
+
+// a set or merge is live while it's part of its key's value, which for one in a batch means the batch record.
+// live operations from a batch are copied as separate records, since they've all been applied already.
+// a tombstone is needed as long as its key stays removed, since an older partition may still hold a set for it.
+// an expired set is needed in the same way as a tombstone if nothing newer has replaced it, so it's rewritten
+// as one. load drops the key from the index when it sees one.
+struct CopyVisitor<'a> {
+    pub job: &'a CompactionJob,
+    pub src_part: Id,
+    pub drop_tombstones: bool,
+    pub run: &'a mut CompactedRun,
+    pub readers: &'a mut BTreeMap<Id,KvDb>, // partitions that merge chains are read from
+}
+
+impl <'a> CopyVisitor<'a> {
+    fn copy(&mut self, key: Vec<u8>, op: &Command, from: Entry) -> Result<()> {
+        let (dest_pos, dest_len) = self.run.dest.append(op)?;
+        self.run.records += 1;
+        let to = Entry::new(self.run.dest_part, dest_pos, dest_len);
+        self.run.moved.push((key, from, to));
+        Ok(())
+    }
+
+    fn tombstone(&mut self, key: Vec<u8>) -> Result<()> {
+        if !self.drop_tombstones {
+            self.run.dest.append(&Command::Remove { key })?;
+            self.run.records += 1;
+        }
+        Ok(())
+    }
+
+    // a merge whose earlier records are all folded into a single set. only worth it if there are any.
+    fn collapses(&self, entry: &Entry) -> bool {
+        self.job.merge_operator.is_some() && !entry.older.is_empty() && self.run.sources.contains(&entry.part)
+    }
+
+    fn collapse(&mut self, key: Vec<u8>, entry: &Entry) -> Result<()> {
+        let chain = entry.clone().into_chain();
+        let blobs = Blobs::new(&self.job.parts.dir);
+        let mut records = vec![];
+        for e in chain.iter() {
+            if !self.readers.contains_key(&e.part) {
+                let kvdb = KvDb::open(&self.job.parts, e.part, self.job.codec.clone())?;
+                self.readers.insert(e.part, kvdb);
+            }
+            let reader = self.readers.get_mut(&e.part).expect("error");
+            if let Some(record) = reader.read_offset(e.offset)?.find(&key) {
+                records.push(blobs.resolve(record)?);
+            }
+        }
+
+        let operator = self.job.merge_operator.as_deref();
+        let value = merge::fold(operator, &key, records, self.job.now)?.unwrap_or_default();
+        let (dest_pos, dest_len) = self.run.dest.append(&Command::Set { key: key.clone(), value, expires: None })?;
+        self.run.records += 1;
+        let to = Entry::new(self.run.dest_part, dest_pos, dest_len);
+        self.run.collapsed.push((key, chain, to));
+        Ok(())
+    }
+}
+
+impl <'a> Visitor for CopyVisitor<'a> {
+    fn command(&mut self, command: Command, pos: Offset, len: u64) -> Result<bool> {
+        for (op, len) in command.ops(len) {
+            self.run.entries += 1;
+
+            let index = &self.job.index;
+            let from = Entry::new(self.src_part, pos, len);
+            match op {
+                Command::Set { ref key, value: _, expires } | Command::Blob { ref key, id: _, expires } if command::is_expired(expires, self.job.now) => {
+                    let entry = index.get(key);
+                    match place(entry, self.src_part, pos) {
+                        Place::Latest => {
+                            self.run.expired.push((key.to_owned(), from));
+                            self.tombstone(key.to_owned())?;
+                        },
+                        Place::Older => self.run.expired.push((key.to_owned(), from)),
+                        Place::Replaced if entry.is_none() => self.tombstone(key.to_owned())?,
+                        Place::Replaced => {},
+                    }
+                },
+                Command::Set { ref key, value: _, expires: _ } | Command::Blob { ref key, id: _, expires: _ } | Command::Merge { ref key, operand: _ } => {
+                    let entry = index.get(key);
+                    match place(entry, self.src_part, pos) {
+                        Place::Replaced => {},
+                        Place::Older if self.collapses(entry.expect("error")) => {},
+                        Place::Latest if self.collapses(entry.expect("error")) => self.collapse(key.to_owned(), entry.expect("error"))?,
+                        Place::Latest | Place::Older => self.copy(key.to_owned(), &op, from)?,
+                    }
+                },
+                Command::Remove { ref key } if !index.contains_key(key) => self.tombstone(key.to_owned())?,
+                Command::Remove { key: _ } => {},
+                Command::Batch { ops: _ } => {},
+            }
+        }
+
+        Ok(!self.job.cancel.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io;
+use std::sync::{Arc,Mutex};
+use std::time::Duration;
+
+use crate::worker::Worker;
+
+// when appended records are forced out to disk
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Durability {
+    // sync after every write, before it returns
+    Always,
+    // sync once window has passed or max_bytes have been written since the last sync, checked on
+    // each write and from a background thread every window, so a write isn't left unsynced when
+    // no more follow. a crash can lose the writes made since the last sync.
+    GroupCommit { window: Duration, max_bytes: u64 },
+    // sync from a background thread every interval
+    Periodic(Duration),
+    // leave it to the operating system
+    Os,
+}
+
+// syncs the current partition from a background thread
+pub struct Syncer {
+    file: Arc<Mutex<Option<File>>>,
+    worker: Worker<io::Error>,
+}
+
+impl Syncer {
+    pub fn start(interval: Duration, file: File) -> Syncer {
+        let file = Arc::new(Mutex::new(Some(file)));
+        let thread_file = file.clone();
+        let worker = Worker::start(interval, move || {
+            match *thread_file.lock().unwrap() {
+                Some(ref f) => f.sync_data(),
+                None => Ok(()),
+            }
+        });
+
+        Syncer {
+            file,
+            worker,
+        }
+    }
+
+    // called when the store moves on to a new partition
+    pub fn set_file(&self, f: File) {
+        *self.file.lock().unwrap() = Some(f);
+    }
+
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.worker.take_error()
+    }
+}
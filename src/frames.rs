@@ -0,0 +1,123 @@
+use std::io::{self,Read,Write,ErrorKind};
+
+use crate::crc::crc32;
+
+// each record is framed as: payload length (u32 le), crc32 of payload (u32 le), payload
+pub const HEADER_LEN: u64 = 8;
+
+#[derive(Debug,PartialEq,PartialOrd)]
+pub struct Frame {
+    pub pos: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Frames<R> {
+    pos: u64,
+    buf: R,
+}
+
+impl<R: Read> Iterator for Frames<R> {
+    type Item = io::Result<Frame>;
+
+    fn next(&mut self) -> Option<io::Result<Frame>> {
+        match read_frame(&mut self.buf) {
+            Ok(Some(data)) => {
+                let pos = self.pos;
+                self.pos += HEADER_LEN + data.len() as u64;
+                Some(Ok(Frame { pos, data }))
+            },
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl <R> Frames<R> {
+    pub fn new(buf: R) -> Frames<R> {
+        Frames::starting_at(buf, 0)
+    }
+
+    // for a reader that's already been positioned at pos
+    pub fn starting_at(buf: R, pos: u64) -> Frames<R> {
+        Frames {
+            pos,
+            buf,
+        }
+    }
+
+    // offset of the next frame to be read, or of the frame that failed to read
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+}
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN as usize + data.len());
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32(data).to_le_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+pub fn write_frame<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    w.write_all(&encode(data))
+}
+
+// reads one frame. returns None on a clean end of input.
+// a truncated frame is reported as UnexpectedEof, a checksum mismatch as InvalidData.
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    let n = read_full(r, &mut header)?;
+    if n == 0 {
+        return Ok(None);
+    } else if n < header.len() {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated record header"));
+    }
+
+    let (len, crc) = parse_header(&header);
+    let len = len as usize;
+
+    // read through take() rather than preallocating, so a corrupt length can't trigger a huge allocation
+    let mut data = Vec::new();
+    if r.take(len as u64).read_to_end(&mut data)? < len {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated record"));
+    }
+
+    check(&data, crc)?;
+
+    Ok(Some(data))
+}
+
+// payload length and crc from a frame header
+pub fn parse_header(header: &[u8; HEADER_LEN as usize]) -> (u32,u32) {
+    let mut len = [0u8; 4];
+    let mut crc = [0u8; 4];
+    len.copy_from_slice(&header[0..4]);
+    crc.copy_from_slice(&header[4..8]);
+    (u32::from_le_bytes(len), u32::from_le_bytes(crc))
+}
+
+pub fn check(data: &[u8], crc: u32) -> io::Result<()> {
+    if crc32(data) != crc {
+        return Err(io::Error::new(ErrorKind::InvalidData, "record checksum mismatch"));
+    }
+    Ok(())
+}
+
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+pub fn is_corruption(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidData)
+}
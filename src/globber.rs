@@ -3,6 +3,7 @@ use glob::glob;
 use std::path::PathBuf;
 use crate::result::*;
 
+#[derive(Clone,Debug)]
 pub struct Globber {
     pub pattern: String,
 }
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,BufReader,BufWriter,Write};
+use std::path::Path;
+
+use crate::result::*;
+use crate::command::Command;
+use crate::frames::{self,Frames};
+use crate::kvdb::Visitor;
+use crate::logdb::Offset;
+
+const MAGIC: &[u8] = b"kvshint2";
+
+const OP_SET: u8 = 0;
+const OP_REMOVE: u8 = 1;
+const OP_SET_EXPIRING: u8 = 2;
+const OP_MERGE: u8 = 3;
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum HintEntry {
+    Set(Offset,u64,Option<u64>), // offset and length of the record, and when it expires
+    Remove(u64), // length of the tombstone
+    Merge(Offset,u64), // offset and length of a merge operand
+}
+
+// key -> offset summary of a partition, so it can be indexed without decoding its values.
+// only the last set or remove per key is kept, along with any merges after it, which is all that
+// replaying the partition would leave behind.
+#[derive(Clone,PartialEq,Debug,Default)]
+pub struct Hint {
+    pub part_size: u64, // size of the partition the hint was built from
+    pub entries: u64, // number of operations in the partition, counting each one in a batch
+    pub keys: BTreeMap<Vec<u8>,Vec<HintEntry>>, // in the order they were written
+}
+
+impl Hint {
+    pub fn new() -> Hint {
+        Hint {
+            part_size: 0,
+            entries: 0,
+            keys: BTreeMap::new(),
+        }
+    }
+
+    // adds an operation from the record at offset, as it's appended to the partition.
+    // nested batches aren't operations, and only count towards entries.
+    pub fn add(&mut self, op: &Command, offset: Offset, len: u64) {
+        self.entries += 1;
+
+        match op {
+            Command::Set{key,value: _,expires} | Command::Blob{key,id: _,expires} => {
+                self.keys.insert(key.clone(), vec![HintEntry::Set(offset, len, *expires)]);
+            },
+            Command::Remove{key} => {
+                self.keys.insert(key.clone(), vec![HintEntry::Remove(len)]);
+            },
+            Command::Merge{key,operand: _operand} => {
+                self.keys.entry(key.clone()).or_default().push(HintEntry::Merge(offset, len));
+            },
+            Command::Batch{ops: _} => {},
+        }
+    }
+
+    // returns None if the hint is missing, damaged or doesn't match the partition size
+    pub fn read(path: &Path, part_size: u64) -> Result<Option<Hint>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(KvsErrorKind::Io(e))?,
+        };
+
+        let mut records = Frames::new(BufReader::new(file));
+        let hint = match records.next() {
+            Some(Ok(header)) => Hint::decode_header(&header.data),
+            Some(Err(ref e)) if frames::is_corruption(e) => None,
+            Some(Err(e)) => Err(KvsErrorKind::Io(e))?,
+            None => None,
+        };
+        let mut hint = match hint {
+            Some(ref hint) if hint.part_size == part_size => hint.to_owned(),
+            _ => return Ok(None),
+        };
+
+        for record in records {
+            let record = match record {
+                Ok(record) => record,
+                Err(ref e) if frames::is_corruption(e) => return Ok(None),
+                Err(e) => Err(KvsErrorKind::Io(e))?,
+            };
+            match Hint::decode_entry(&record.data) {
+                Some((key, entry)) => hint.keys.entry(key).or_insert_with(Vec::new).push(entry),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Some(hint))
+    }
+
+    // written to a temporary file and renamed into place, so a reader never sees half a hint
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("hint.tmp");
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)
+            .map_err(KvsErrorKind::Io)?;
+        let mut w = BufWriter::new(file);
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&self.part_size.to_le_bytes());
+        header.extend_from_slice(&self.entries.to_le_bytes());
+        frames::write_frame(&mut w, &header)
+            .map_err(KvsErrorKind::Io)?;
+
+        for (key, entry) in self.keys.iter().flat_map(|(key, entries)| entries.iter().map(move |e| (key, e))) {
+            let mut record = vec![];
+            match entry {
+                HintEntry::Set(offset, len, None) => {
+                    record.push(OP_SET);
+                    record.extend_from_slice(&offset.to_le_bytes());
+                    record.extend_from_slice(&len.to_le_bytes());
+                },
+                HintEntry::Set(offset, len, Some(expires)) => {
+                    record.push(OP_SET_EXPIRING);
+                    record.extend_from_slice(&offset.to_le_bytes());
+                    record.extend_from_slice(&len.to_le_bytes());
+                    record.extend_from_slice(&expires.to_le_bytes());
+                },
+                HintEntry::Remove(len) => {
+                    record.push(OP_REMOVE);
+                    record.extend_from_slice(&len.to_le_bytes());
+                },
+                HintEntry::Merge(offset, len) => {
+                    record.push(OP_MERGE);
+                    record.extend_from_slice(&offset.to_le_bytes());
+                    record.extend_from_slice(&len.to_le_bytes());
+                },
+            }
+            record.extend_from_slice(key);
+            frames::write_frame(&mut w, &record)
+                .map_err(KvsErrorKind::Io)?;
+        }
+
+        w.flush()
+            .and_then(|_| w.get_ref().sync_all())
+            .map_err(KvsErrorKind::Io)?;
+        fs::rename(&tmp, path)
+            .map_err(KvsErrorKind::Io)?;
+
+        Ok(())
+    }
+
+    fn decode_header(data: &[u8]) -> Option<Hint> {
+        if data.len() != MAGIC.len() + 16 || &data[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        Some(Hint {
+            part_size: read_u64(&data[MAGIC.len()..MAGIC.len() + 8]),
+            entries: read_u64(&data[MAGIC.len() + 8..]),
+            keys: BTreeMap::new(),
+        })
+    }
+
+    fn decode_entry(data: &[u8]) -> Option<(Vec<u8>,HintEntry)> {
+        let (entry, key) = match data.first() {
+            Some(&OP_SET) if data.len() >= 17 => {
+                (HintEntry::Set(read_u64(&data[1..9]), read_u64(&data[9..17]), None), &data[17..])
+            },
+            Some(&OP_SET_EXPIRING) if data.len() >= 25 => {
+                (HintEntry::Set(read_u64(&data[1..9]), read_u64(&data[9..17]), Some(read_u64(&data[17..25]))), &data[25..])
+            },
+            Some(&OP_MERGE) if data.len() >= 17 => {
+                (HintEntry::Merge(read_u64(&data[1..9]), read_u64(&data[9..17])), &data[17..])
+            },
+            Some(&OP_REMOVE) if data.len() >= 9 => {
+                (HintEntry::Remove(read_u64(&data[1..9])), &data[9..])
+            },
+            _ => return None,
+        };
+        Some((key.to_vec(), entry))
+    }
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut n = [0u8; 8];
+    n.copy_from_slice(data);
+    u64::from_le_bytes(n)
+}
+
+// builds a hint by scanning a partition
+pub struct HintBuilder {
+    pub hint: Hint,
+}
+
+impl Visitor for HintBuilder {
+    fn command(&mut self, c: Command, offset: Offset, len: u64) -> Result<bool> {
+        for (op, len) in c.ops(len) {
+            if let Command::Batch{ops: _} = op {
+                Err(KvsErrorKind::Codec("nested batch".to_owned()))?;
+            }
+            self.hint.add(&op, offset, len);
+        }
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::fs::{self,File,OpenOptions};
+use std::io::{BufReader,Seek,SeekFrom};
+use std::path::{Path,PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::result::*;
+use crate::batch::WriteBatch;
+use crate::command::Command;
+use crate::frames;
+use crate::parts::Parts;
+use crate::{KvStore,KvStoreParams};
+
+// writes to several keyspaces, applied by Keyspaces::write as a single unit
+#[derive(Clone,PartialEq,Debug,Default)]
+pub struct KeyspaceBatch {
+    batches: BTreeMap<String,WriteBatch>,
+}
+
+impl KeyspaceBatch {
+    pub fn new() -> KeyspaceBatch {
+        KeyspaceBatch::default()
+    }
+
+    // the writes to the named keyspace
+    pub fn keyspace(&mut self, name: &str) -> &mut WriteBatch {
+        self.batches.entry(name.to_owned()).or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.values().all(|b| b.is_empty())
+    }
+}
+
+// a batch spanning keyspaces, logged before any of it is applied
+#[derive(Serialize,Deserialize)]
+struct Pending {
+    batches: Vec<(String,Command)>,
+}
+
+// independent keyspaces sharing a directory. each one is a KvStore in a subdirectory named after it,
+// with its own index, partitions and params.
+pub struct Keyspaces {
+    dir: PathBuf,
+    journal: File,
+    stores: BTreeMap<String,KvStore>,
+}
+
+impl Keyspaces {
+    pub fn open(path: &Path) -> Result<Keyspaces> {
+        Keyspaces::open_with_params(path, BTreeMap::new())
+    }
+
+    // opens every keyspace in the directory, and creates any in params that don't exist yet.
+    // keyspaces missing from params are opened with the defaults. a keyspace is a subdirectory
+    // with a valid name holding a store's FORMAT marker, so anything else in there is left alone.
+    pub fn open_with_params(path: &Path, mut params: BTreeMap<String,KvStoreParams>) -> Result<Keyspaces> {
+        for entry in fs::read_dir(path).map_err(KvsErrorKind::Io)? {
+            let entry = entry.map_err(KvsErrorKind::Io)?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if check_name(&name).is_ok() && Parts::new(&entry.path()).format_path().is_file() {
+                params.entry(name).or_default();
+            }
+        }
+
+        let journal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false) // a pending batch is replayed from it on open
+            .open(path.join("JOURNAL"))
+            .map_err(KvsErrorKind::Io)?;
+
+        let mut keyspaces = Keyspaces {
+            dir: path.to_owned(),
+            journal,
+            stores: BTreeMap::new(),
+        };
+        for (name, params) in params {
+            keyspaces.open_store(&name, params)?;
+        }
+        keyspaces.recover()?;
+
+        Ok(keyspaces)
+    }
+
+    // opens the named keyspace, creating it if it doesn't exist
+    pub fn open_keyspace(&mut self, name: &str, params: KvStoreParams) -> Result<&mut KvStore> {
+        self.recover()?;
+        self.open_store(name, params)
+    }
+
+    // a keyspace is only handed out once any journalled batch has been finished, so nothing
+    // written through it directly can be overwritten by replaying the batch later
+    pub fn keyspace(&mut self, name: &str) -> Result<&mut KvStore> {
+        self.recover()?;
+        self.store(name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.stores.keys().cloned().collect()
+    }
+
+    // closes the keyspace and deletes everything in it
+    pub fn remove_keyspace(&mut self, name: &str) -> Result<()> {
+        self.recover()?;
+        let store = self.stores.remove(name)
+            .ok_or_else(|| KvsErrorKind::NotFound(format!("keyspace {}", name)))?;
+        drop(store);
+        fs::remove_dir_all(self.dir.join(name))
+            .map_err(KvsErrorKind::Io)?;
+        Ok(())
+    }
+
+    fn open_store(&mut self, name: &str, params: KvStoreParams) -> Result<&mut KvStore> {
+        check_name(name)?;
+        if !self.stores.contains_key(name) {
+            let dir = self.dir.join(name);
+            fs::create_dir_all(&dir)
+                .map_err(KvsErrorKind::Io)?;
+            let store = KvStore::open_with_params(&dir, params)?;
+            self.stores.insert(name.to_owned(), store);
+        }
+        Ok(self.stores.get_mut(name).expect("error"))
+    }
+
+    fn store(&mut self, name: &str) -> Result<&mut KvStore> {
+        self.stores.get_mut(name)
+            .ok_or_else(|| KvsErrorKind::NotFound(format!("keyspace {}", name)).into())
+    }
+
+    // applies every write in the batch or none of them. a batch touching more than one keyspace
+    // is journalled first, and if applying it fails part way it's finished off before any keyspace
+    // is used again, or when the keyspaces are next opened.
+    pub fn write(&mut self, batch: KeyspaceBatch) -> Result<()> {
+        self.recover()?;
+
+        let batches: Vec<(String,WriteBatch)> = batch.batches.into_iter()
+            .filter(|(_, b)| !b.is_empty())
+            .collect();
+        for (name, _) in batches.iter() {
+            self.store(name)?;
+        }
+
+        if batches.len() < 2 {
+            for (name, batch) in batches {
+                self.store(&name)?.write(batch)?;
+            }
+            return Ok(());
+        }
+
+        let pending = Pending {
+            batches: batches.into_iter()
+                .map(|(name, batch)| (name, batch.into_command()))
+                .collect(),
+        };
+        let record = serde_json::to_vec(&pending)
+            .map_err(KvsErrorKind::ParserError)?;
+        self.journal.set_len(0)
+            .and_then(|_| self.journal.seek(SeekFrom::Start(0)))
+            .and_then(|_| frames::write_frame(&mut self.journal, &record))
+            .and_then(|_| self.journal.sync_all())
+            .map_err(KvsErrorKind::Io)?;
+
+        self.apply(pending)
+    }
+
+    // finishes a batch that was journalled but might not have been applied. every way into a keyspace
+    // calls this first, so nothing has been written since the batch and applying it again can't undo anything.
+    fn recover(&mut self) -> Result<()> {
+        self.journal.seek(SeekFrom::Start(0))
+            .map_err(KvsErrorKind::Io)?;
+        let record = match frames::read_frame(&mut BufReader::new(&self.journal)) {
+            Ok(Some(record)) => record,
+            Ok(None) => return Ok(()),
+            // a torn journal entry means none of the batch was applied
+            Err(ref e) if frames::is_corruption(e) => return self.clear(),
+            Err(e) => Err(KvsErrorKind::Io(e))?,
+        };
+
+        let pending: Pending = serde_json::from_slice(&record)
+            .map_err(KvsErrorKind::ParserError)?;
+        for (name, _) in pending.batches.iter() {
+            self.open_store(name, KvStoreParams::new())?;
+        }
+        self.apply(pending)
+    }
+
+    fn apply(&mut self, pending: Pending) -> Result<()> {
+        for (name, command) in pending.batches.iter() {
+            self.store(name)?.write(WriteBatch::from_command(command.clone()))?;
+        }
+        // the journal entry can only go once every part of the batch is on disk
+        for (name, _) in pending.batches.iter() {
+            self.store(name)?.sync()?;
+        }
+        self.clear()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.journal.set_len(0)
+            .and_then(|_| self.journal.sync_all())
+            .map_err(KvsErrorKind::Io)?;
+        Ok(())
+    }
+}
+
+// names become directory names, so they're kept to a safe set of characters
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        Err(KvsErrorKind::Config(format!("invalid keyspace name: {:?}", name)))?;
+    }
+    Ok(())
+}
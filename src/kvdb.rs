@@ -1,68 +1,101 @@
 use std::fs::File;
+use std::sync::Arc;
 
 use crate::logdb::{self,LogDb,Offset};
+use crate::frames::HEADER_LEN;
+use crate::parts::{Parts,Id};
+use crate::codec::Codec;
 use crate::result::*;
 use crate::command::*;
 
-#[derive(Copy,Clone,PartialEq,Debug)]
-struct Parser;
-
-impl Parser {
-    pub fn parse(&self, line: &str) -> Result<Command> {
-        let command = serde_json::from_str(line)
-            .map_err(|e| KvsErrorKind::ParserError(e))?;
-        Ok(command)
-    }
-
-    pub fn encode(&self, c: Command) -> Result<String> {
-        let s = serde_json::to_string(&c)
-            .map_err(|e| KvsErrorKind::ParserError(e))?;
-        Ok(s)
-    }
-}
-
 pub trait Visitor {
-    fn command(&mut self, command: Command, pos: Offset) -> Result<bool>;
+    // len is the size of the whole record on disk, framing included
+    fn command(&mut self, command: Command, pos: Offset, len: u64) -> Result<bool>;
 }
 
-struct ParserVisitor<V: Visitor> {
-    parser: Parser,
+struct DecodingVisitor<'a, V: Visitor> {
+    codec: &'a dyn Codec,
     inner: V,
 }
 
-impl <V: Visitor> logdb::Visitor for ParserVisitor<V> {
-    fn line(&mut self, line: String, pos: Offset) -> Result<bool> {
-        let obj = self.parser.parse(&line)?;
-        self.inner.command(obj, pos)
+impl <'a, V: Visitor> logdb::Visitor for DecodingVisitor<'a, V> {
+    fn record(&mut self, record: Vec<u8>, pos: Offset, len: u64) -> Result<bool> {
+        let obj = self.codec.decode(&record)?;
+        self.inner.command(obj, pos, len)
     }
 }
 
 pub struct KvDb {
-    parser: Parser,
+    codec: Arc<dyn Codec>,
     logdb: LogDb,
 }
 
 impl KvDb {
-    pub fn new(file: File) -> Result<KvDb> {
+    // a new partition. records are always framed in those.
+    pub fn new(id: Id, file: File, codec: Arc<dyn Codec>) -> Result<KvDb> {
+        KvDb::with_framing(id, file, codec, true)
+    }
+
+    // an existing partition, which may be from before records were framed
+    pub fn open(parts: &Parts, id: Id, codec: Arc<dyn Codec>) -> Result<KvDb> {
+        KvDb::with_framing(id, parts.open(id)?, codec, parts.is_framed(id))
+    }
+
+    pub fn with_framing(id: Id, file: File, codec: Arc<dyn Codec>, framed: bool) -> Result<KvDb> {
         Ok(KvDb {
-            parser: Parser,
-            logdb: LogDb::new(file)?,
+            codec,
+            logdb: LogDb::new(id, file, framed)?,
         })
     }
 
-    pub fn visit<V: Visitor>(&mut self, visitor: V) -> Result<V> {
-        let parser = ParserVisitor { parser: self.parser, inner: visitor };
-        let parser = self.logdb.visit(parser)?;
-        Ok(parser.inner)
+    pub fn visit<V: Visitor>(&self, visitor: V) -> Result<V> {
+        self.visit_from(0, visitor)
+    }
+
+    pub fn visit_from<V: Visitor>(&self, offset: Offset, visitor: V) -> Result<V> {
+        let decoder = DecodingVisitor { codec: &*self.codec, inner: visitor };
+        let decoder = self.logdb.visit_from(offset, decoder)?;
+        Ok(decoder.inner)
+    }
+
+    // returns the offset and on-disk length of the new record
+    pub fn append(&self, command: &Command) -> Result<(Offset,u64)> {
+        let record = self.codec.encode(command)?;
+        let pos = self.logdb.append(&record)?;
+        Ok((pos, HEADER_LEN + record.len() as u64))
+    }
+
+    pub fn torn_tail(&self) -> Result<Option<Offset>> {
+        self.logdb.torn_tail()
+    }
+
+    pub fn is_incomplete(&self, offset: Offset) -> Result<bool> {
+        self.logdb.is_incomplete(offset)
+    }
+
+    pub fn read_tail(&self, offset: Offset) -> Result<Vec<u8>> {
+        self.logdb.read_tail(offset)
+    }
+
+    pub fn truncate(&self, offset: Offset) -> Result<()> {
+        self.logdb.truncate(offset)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.logdb.flush()
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.logdb.sync()
     }
 
-    pub fn append(&mut self, command: Command) -> Result<Offset> {
-        self.logdb.append(self.parser.encode(command)?)
+    pub fn try_clone_file(&self) -> Result<File> {
+        self.logdb.try_clone_file()
     }
 
-    pub fn read_offset(&mut self, offset: Offset) -> Result<Command> {
-        let line = self.logdb.read_offset(offset)?;
-        let command = self.parser.parse(&line)?;
+    pub fn read_offset(&self, offset: Offset) -> Result<Command> {
+        let record = self.logdb.read_offset(offset)?;
+        let command = self.codec.decode(&record)?;
         Ok(command)
     }
 }
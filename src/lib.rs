@@ -1,30 +1,165 @@
-use std::collections::BTreeMap;
-use std::path::Path;
+use std::collections::{BTreeMap,BTreeSet};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration,Instant,SystemTime};
+use std::path::{Path,PathBuf};
+use std::ops::{Bound,RangeBounds};
 use std::fs::{self,File,OpenOptions};
+use std::io::Write;
 
 pub mod result;
 pub mod command;
 pub mod kvdb;
+pub mod codec;
 pub mod logdb;
 pub mod lines;
+pub mod frames;
+pub mod crc;
 pub mod globber;
 pub mod parts;
+pub mod hint;
+pub mod manifest;
+pub mod compaction;
+pub mod rotation;
+pub mod durability;
+pub mod worker;
+pub mod batch;
+pub mod transaction;
+pub mod snapshot;
+pub mod scan;
+pub mod cas;
+pub mod merge;
+pub mod watch;
+pub mod tail;
+pub mod keyspace;
+pub mod secondary;
+pub mod blob;
+pub mod shared;
 
 pub use result::*;
 use kvdb::{KvDb,Visitor};
-use command::Command;
-use parts::{Parts,Id};
+use command::{self as cmd,Command};
+use parts::{Parts,Id,Format};
+use codec::Codec;
+use hint::{Hint,HintBuilder,HintEntry};
+use compaction::{Compaction,CompactionJob,CompactionOutput,CompactedRun};
+use rotation::{RotationPolicy,PartInfo};
+use merge::MergeOperator;
+use watch::{Watchers,Watch,WatchId,Event};
+use durability::{Durability,Syncer};
+use secondary::{IndexDef,Indexes};
+use blob::{Blobs,BlobId};
+pub use batch::WriteBatch;
+pub use transaction::Transaction;
+pub use snapshot::Snapshot;
+use snapshot::Retention;
+pub use scan::{Scan,Cursor};
+pub use cas::CasResult;
+pub use tail::{Tail,Position,Change};
+pub use keyspace::{Keyspaces,KeyspaceBatch};
+pub use shared::SharedKvStore;
+use shared::Shared;
 
 pub const DEFAULT_FILE_NAME: &str = "kvs.json";
 
 use logdb::Offset;
-type OffsetIndex = BTreeMap<String,(Id,Offset)>;
-type PartitionsMap = BTreeMap<Id,KvDb>;
+// a persistent map, so a snapshot or scan holding it costs a write only the path to the key it changes
+pub(crate) type OffsetIndex = im::OrdMap<Vec<u8>,Entry>;
+pub(crate) type PartitionsMap = BTreeMap<Id,Arc<KvDb>>;
+
+// where the latest record for a key lives
+#[derive(Clone,PartialEq,Debug)]
+pub struct Entry {
+    pub part: Id,
+    pub offset: Offset,
+    pub len: u64, // size of the record on disk
+    pub seq: u64, // sequence number of the write since the store was opened, 0 if it was loaded from disk
+    pub expires: Option<u64>, // when the key expires, in milliseconds since the unix epoch
+    pub older: Vec<Entry>, // earlier records that a merge is applied to, oldest first. empty for anything else
+}
+
+impl Entry {
+    pub fn new(part: Id, offset: Offset, len: u64) -> Entry {
+        Entry {
+            part,
+            offset,
+            len,
+            seq: 0,
+            expires: None,
+            older: vec![],
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        cmd::is_expired(self.expires, now)
+    }
+
+    // whether both refer to the same record
+    pub fn is_at(&self, other: &Entry) -> bool {
+        self.part == other.part && self.offset == other.offset
+    }
+
+    // every record making up the value, oldest first, ending with this one
+    pub fn into_chain(mut self) -> Vec<Entry> {
+        let mut chain = std::mem::take(&mut self.older);
+        chain.push(self);
+        chain
+    }
+
+    // points whichever of the records making up the value is at from to to instead
+    pub fn relocate(&mut self, from: &Entry, to: &Entry) -> bool {
+        let moved = if self.is_at(from) {
+            Some(self)
+        } else {
+            self.older.iter_mut().find(|e| e.is_at(from))
+        };
+        match moved {
+            Some(e) => {
+                e.part = to.part;
+                e.offset = to.offset;
+                e.len = to.len;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // replaces the records at the start of the chain with to, a set holding the value they merge into.
+    // returns false if the value isn't made from them any more.
+    pub fn collapse(&mut self, chain: &[Entry], to: &Entry) -> bool {
+        let current = self.older.iter().chain(std::iter::once(&*self));
+        if chain.len() > self.older.len() + 1 || !chain.iter().zip(current).all(|(a, b)| a.is_at(b)) {
+            return false;
+        }
+
+        if chain.len() == self.older.len() + 1 {
+            self.older.clear();
+            self.part = to.part;
+            self.offset = to.offset;
+            self.len = to.len;
+        } else {
+            let rest = self.older.split_off(chain.len());
+            self.older = vec![to.clone()];
+            self.older.extend(rest);
+        }
+        true
+    }
+}
 
 #[derive(Clone,Debug)]
 pub struct KvStoreParams {
     pub max_part_size: u64, // max partition file size in bytes before creating new partition file
+    pub rotation: Vec<Arc<dyn RotationPolicy>>, // further conditions for starting a new partition file, checked along with max_part_size
     pub compact_garbage_threshold: u32, // number of log entries per key before compaction is triggered
+    pub compact_dead_ratio: Option<f64>, // share of dead bytes across the store at which compaction is also triggered
+    pub compact_part_garbage_ratio: f64, // share of dead bytes at which a sealed partition gets rewritten by compaction
+    pub codec: Arc<dyn Codec>, // record encoding for new stores. existing stores keep the codec they were created with
+    pub durability: Durability, // when writes are synced to disk. the sync thread for Periodic and GroupCommit is only started on open
+    pub sweep_interval: Option<Duration>, // how often keys that have expired are removed, from a background thread for a SharedKvStore and by writes otherwise
+    pub merge_operator: Option<Arc<dyn MergeOperator>>, // combines merge operands with values. needed to merge or read merged keys
+    pub indexes: Vec<IndexDef>, // fields of json values to keep secondary indexes on
+    pub blob_threshold: Option<usize>, // values longer than this are written to blob files of their own rather than the log
 }
 
 impl KvStoreParams {
@@ -36,7 +171,16 @@ impl Default for KvStoreParams {
     fn default() -> KvStoreParams {
         KvStoreParams {
             max_part_size: 1_000_000,
+            rotation: vec![],
             compact_garbage_threshold: 10,
+            compact_dead_ratio: None,
+            compact_part_garbage_ratio: 0.5,
+            codec: Arc::new(codec::JsonCodec),
+            durability: Durability::Os,
+            sweep_interval: None,
+            merge_operator: None,
+            indexes: vec![],
+            blob_threshold: None,
         }
     }
 }
@@ -44,52 +188,208 @@ impl Default for KvStoreParams {
 #[derive(Clone,Debug)]
 pub struct KvStoreMetrics {
     pub entries: u64,
+    pub parts: BTreeMap<Id,PartStats>,
+    pub recovery: Option<RecoveryReport>, // set when open had to discard a torn write
 }
 
 impl KvStoreMetrics {
     pub fn new() -> KvStoreMetrics {
         KvStoreMetrics {
             entries: 0,
+            parts: BTreeMap::new(),
+            recovery: None,
         }
     }
+
+    // a record was written and holds live data
+    pub fn appended(&mut self, part: Id, len: u64) {
+        let stats = self.parts.entry(part).or_default();
+        stats.total_bytes += len;
+        stats.live_bytes += len;
+        stats.records += 1;
+    }
+
+    // a record was overwritten or removed, along with any it was merged with
+    pub fn superseded(&mut self, entry: &Entry) {
+        if let Some(stats) = self.parts.get_mut(&entry.part) {
+            stats.live_bytes = stats.live_bytes.saturating_sub(entry.len);
+        }
+        for e in entry.older.iter() {
+            self.superseded(e);
+        }
+    }
+
+    pub fn live_bytes(&self) -> u64 {
+        self.parts.values().map(|p| p.live_bytes).sum()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.parts.values().map(|p| p.total_bytes).sum()
+    }
+
+    pub fn dead_bytes(&self) -> u64 {
+        self.total_bytes() - self.live_bytes()
+    }
+
+    pub fn dead_ratio(&self) -> f64 {
+        ratio(self.dead_bytes(), self.total_bytes())
+    }
+}
+
+// garbage accounting for a partition. tombstones count as live, since they shadow
+// older values until compaction can drop them.
+#[derive(Copy,Clone,PartialEq,Debug,Default)]
+pub struct PartStats {
+    pub live_bytes: u64,
+    pub total_bytes: u64,
+    pub records: u64,
+}
+
+impl PartStats {
+    pub fn dead_bytes(&self) -> u64 {
+        self.total_bytes - self.live_bytes
+    }
+
+    pub fn dead_ratio(&self) -> f64 {
+        ratio(self.dead_bytes(), self.total_bytes)
+    }
+}
+
+fn ratio(n: u64, d: u64) -> f64 {
+    if d == 0 {
+        0.0
+    } else {
+        n as f64 / d as f64
+    }
+}
+
+// describes a partially written record found at the end of the current partition on open
+#[derive(Clone,Debug,PartialEq)]
+pub struct RecoveryReport {
+    pub part: Id,
+    pub offset: Offset, // where the partition was truncated
+    pub bytes: u64, // number of bytes removed
+    pub saved_to: PathBuf, // side file the removed bytes were appended to
 }
 
 pub struct KvStore {
     parts: Parts,
+    codec: Arc<dyn Codec>,
     current_part: Id,
-    kvdbs: PartitionsMap,
-    store: OffsetIndex,
+    current_since: SystemTime, // when the current partition was started
+    live: Vec<Id>, // partitions in replay order, as listed in the manifest
+    shared: Arc<Shared>, // the index and partitions, read from by every SharedKvStore handle
+    retention: Retention,
+    compaction: Option<Compaction>,
+    seq: u64, // sequence number of the last write
+    last_sweep: Instant,
+    watchers: Watchers,
+    indexes: Indexes,
+    blobs: Blobs,
+    next_blob: BlobId,
+    hint: Hint, // the current partition's, added to as it's written and written out when it's sealed
+    unsynced_bytes: u64, // written to the current partition since it was last synced
+    last_sync: Instant,
+    syncer: Option<Syncer>,
     pub params: KvStoreParams,
     pub metrics: KvStoreMetrics,
 }
 
 struct Loader {
     pub part: Id,
+    pub now: u64, // keys that expired before this are left out of the index
     pub index: OffsetIndex,
     pub metrics: KvStoreMetrics,
 }
 
 impl Loader {
-    pub fn new(part: Id, index: OffsetIndex) -> Loader {
+    pub fn new() -> Loader {
         Loader {
             metrics: KvStoreMetrics::new(),
-            part: part,
-            index: index,
+            part: 0,
+            now: cmd::now(),
+            index: OffsetIndex::new(),
+        }
+    }
+
+    // a hint only has the commands for each key that replaying the partition would leave behind,
+    // so the bytes of everything else in the partition are dead
+    pub fn hint(&mut self, hint: Hint) {
+        self.metrics.entries += hint.entries;
+        let stats = self.metrics.parts.entry(self.part).or_default();
+        stats.total_bytes += hint.part_size;
+        stats.records += hint.entries;
+
+        for (key, entries) in hint.keys {
+            for entry in entries {
+                match entry {
+                    HintEntry::Set(_offset, len, expires) if cmd::is_expired(expires, self.now) => {
+                        self.remove(&key, len);
+                    },
+                    HintEntry::Set(offset, len, expires) => {
+                        self.set(key.clone(), Entry { expires, ..Entry::new(self.part, offset, len) });
+                    },
+                    HintEntry::Remove(len) => {
+                        self.remove(&key, len);
+                    },
+                    HintEntry::Merge(offset, len) => {
+                        self.merge(key.clone(), Entry::new(self.part, offset, len));
+                    },
+                }
+            }
         }
     }
+
+    fn set(&mut self, key: Vec<u8>, entry: Entry) {
+        self.metrics.parts.entry(self.part).or_default().live_bytes += entry.len;
+        if let Some(old) = self.index.insert(key, entry) {
+            self.metrics.superseded(&old);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8], len: u64) {
+        self.metrics.parts.entry(self.part).or_default().live_bytes += len;
+        if let Some(old) = self.index.remove(key) {
+            self.metrics.superseded(&old);
+        }
+    }
+
+    // the records the merge applies to stay live
+    fn merge(&mut self, key: Vec<u8>, mut entry: Entry) {
+        self.metrics.parts.entry(self.part).or_default().live_bytes += entry.len;
+        if let Some(old) = self.index.remove(&key) {
+            entry.older = old.into_chain();
+        }
+        self.index.insert(key, entry);
+    }
 }
 
 impl Visitor for Loader {
-    fn command(&mut self, c: Command, offset: Offset) -> Result<bool> {
-        self.metrics.entries += 1;
+    fn command(&mut self, c: Command, offset: Offset, len: u64) -> Result<bool> {
+        let stats = self.metrics.parts.entry(self.part).or_default();
+        stats.total_bytes += len;
+        stats.records += 1;
 
-        match c {
-            Command::Set{key,value: _value} => {
-                self.index.insert(key, (self.part, offset));
-            },
-            Command::Remove{key} => {
-                self.index.remove(&key);
-            },
+        // a batch is a single record, so it's either replayed whole or was never written
+        for (op, len) in c.ops(len) {
+            self.metrics.entries += 1;
+
+            match op {
+                // an expired set still replaces older values, so it's treated like a remove
+                Command::Set{key,value: _,expires} | Command::Blob{key,id: _,expires} if cmd::is_expired(expires, self.now) => {
+                    self.remove(&key, len);
+                },
+                Command::Set{key,value: _,expires} | Command::Blob{key,id: _,expires} => {
+                    self.set(key, Entry { expires, ..Entry::new(self.part, offset, len) });
+                },
+                Command::Remove{key} => {
+                    self.remove(&key, len);
+                },
+                Command::Merge{key,operand: _operand} => {
+                    self.merge(key, Entry::new(self.part, offset, len));
+                },
+                Command::Batch{ops: _} => Err(KvsErrorKind::Codec("nested batch".to_owned()))?,
+            }
         }
 
         Ok(true)
@@ -97,163 +397,817 @@ impl Visitor for Loader {
 }
 
 impl KvStore {
-    pub fn cur<'a>(&'a self) -> &'a KvDb {
+    pub fn cur(&self) -> Arc<KvDb> {
         self.part(self.current_part).expect("error")
     }
 
-    pub fn cur_mut<'a>(&'a mut self) -> &'a mut KvDb {
-        self.part_mut(self.current_part).expect("error")
+    pub fn part(&self, id: Id) -> Result<Arc<KvDb>> {
+        self.shared.part(id)
+    }
+    
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
     }
 
-    pub fn part<'a>(&'a self, id: Id) -> Result<&'a KvDb> {
-        Ok(self.kvdbs.get(&id).ok_or_else(|| KvsErrorKind::InvalidPartition(id))?)
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.before_write()?;
+        self.append(Command::Set{key: key.to_vec(), value: value.to_vec(), expires: None})
     }
 
-    pub fn part_mut<'a>(&'a mut self, id: Id) -> Result<&'a mut KvDb> {
-        Ok(self.kvdbs.get_mut(&id).ok_or_else(|| KvsErrorKind::InvalidPartition(id))?)
+    // the key disappears once ttl has passed
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
     }
-    
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let pos = self.cur_mut().append(Command::Set{key: key.clone(), value: value})?;
-        self.store.insert(key, (self.current_part,pos));
-        self.metrics.entries += 1;
 
+    pub fn set_bytes_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.before_write()?;
+        let expires = SystemTime::now().checked_add(ttl)
+            .ok_or_else(|| KvsErrorKind::Config(format!("ttl of {:?} is too long", ttl)))?;
+        let expires = cmd::timestamp(expires);
+        self.append(Command::Set{key: key.to_vec(), value: value.to_vec(), expires: Some(expires)})
+    }
+
+    // folds operand into the key's value with the configured merge operator. the operand is
+    // logged as it is, and only combined with the value when it's read or compacted.
+    pub fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        self.merge_bytes(key.as_bytes(), operand.as_bytes())
+    }
+
+    pub fn merge_bytes(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        if self.params.merge_operator.is_none() {
+            Err(KvsErrorKind::Config("no merge operator configured".to_owned()))?;
+        }
+
+        self.before_write()?;
+        self.append(Command::Merge{key: key.to_vec(), operand: operand.to_vec()})
+    }
+
+    // events for changes to key, from the next write on. stops when the receiver is dropped.
+    pub fn watch(&mut self, key: &[u8]) -> Receiver<Event> {
+        self.watchers.channel(Watch::Key(key.to_vec()))
+    }
+
+    pub fn watch_prefix(&mut self, prefix: &[u8]) -> Receiver<Event> {
+        self.watchers.channel(Watch::Prefix(prefix.to_vec()))
+    }
+
+    // calls f with each event for keys matching watch, on the writing thread, once the write has succeeded
+    pub fn watch_with<F: FnMut(&Event) + Send + 'static>(&mut self, watch: Watch, f: F) -> WatchId {
+        self.watchers.callback(watch, Box::new(f))
+    }
+
+    pub fn unwatch(&mut self, id: WatchId) {
+        self.watchers.remove(id);
+    }
+
+    // writes new in place of the key's value if it currently holds expected. None stands for a missing key,
+    // both as the expected value and as the new one, which removes the key.
+    pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<CasResult<String>> {
+        let result = self.compare_and_swap_bytes(
+            key.as_bytes(),
+            expected.as_ref().map(|v| v.as_bytes()),
+            new.as_ref().map(|v| v.as_bytes()))?;
+        match result {
+            CasResult::Written => Ok(CasResult::Written),
+            CasResult::Mismatch(None) => Ok(CasResult::Mismatch(None)),
+            CasResult::Mismatch(Some(current)) => Ok(CasResult::Mismatch(Some(into_string(current)?))),
+        }
+    }
+
+    pub fn compare_and_swap_bytes(&mut self, key: &[u8], expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<CasResult<Vec<u8>>> {
+        let current = self.get_bytes(key)?;
+        if current.as_ref().map(|v| &v[..]) != expected {
+            return Ok(CasResult::Mismatch(current));
+        }
+
+        match new {
+            Some(value) => self.set_bytes(key, value)?,
+            None if current.is_some() => self.remove_bytes(key)?,
+            None => {},
+        }
+        Ok(CasResult::Written)
+    }
+
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<CasResult<String>> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    pub fn set_bytes_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<CasResult<Vec<u8>>> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    pub fn remove_if_equals(&mut self, key: String, expected: String) -> Result<CasResult<String>> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    pub fn remove_bytes_if_equals(&mut self, key: &[u8], expected: &[u8]) -> Result<CasResult<Vec<u8>>> {
+        self.compare_and_swap_bytes(key, Some(expected), None)
+    }
+
+    // writes tombstones for every key that has expired, and returns how many there were
+    pub fn sweep_expired(&mut self) -> Result<usize> {
+        let now = cmd::now();
+        let mut batch = WriteBatch::new();
+        for (key, entry) in self.shared.index().iter() {
+            if entry.is_expired(now) {
+                batch.remove_bytes(key);
+            }
+        }
+
+        let expired = batch.len();
+        if expired > 0 {
+            self.append_command(batch.into_command(), true)?;
+        }
+        Ok(expired)
+    }
+
+    fn sweep_if_due(&mut self) -> Result<()> {
+        match self.params.sweep_interval {
+            Some(interval) if self.last_sweep.elapsed() >= interval => {
+                self.last_sweep = Instant::now();
+                self.sweep_expired().map(|_| ())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // background work that's picked up whenever there's a write
+    fn before_write(&mut self) -> Result<()> {
+        self.poll_compaction()?;
+        self.sweep_if_due()
+    }
+
+    // the entry for key, unless it's missing or has expired
+    fn entry(&self, key: &[u8]) -> Option<Entry> {
+        self.shared.entry(key)
+    }
+
+    // applies every operation in the batch, or none of them if the write fails
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.before_write()?;
+        self.append(batch.into_command())
+    }
+
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.seq)
+    }
+
+    // writes the transaction's changes as a single batch, unless something it read has changed
+    pub fn commit(&mut self, transaction: Transaction) -> Result<()> {
+        transaction.validate(self)?;
+        self.write(transaction.into_writes())
+    }
+
+    // a read-only view of the store as it is now. it isn't affected by later writes or compactions,
+    // and keeps the partitions it reads from on disk until it's dropped, as long as the store isn't reopened.
+    pub fn snapshot(&self) -> Snapshot {
+        let pin = self.retention.pin(&self.live);
+        Snapshot::new(self.shared.index(), self.parts.clone(), self.codec.clone(), self.params.merge_operator.clone(), self.seq, pin)
+    }
+
+    // the sequence number of the key's current value, None if the key doesn't exist.
+    // changes whenever the key is written.
+    pub fn version(&self, key: &[u8]) -> Option<u64> {
+        self.entry(key).map(|e| e.seq)
+    }
+
+    // logs a command and applies it to the index once it's been written
+    fn append(&mut self, command: Command) -> Result<()> {
+        self.append_command(command, false)
+    }
+
+    // expired is set when the command removes keys that have expired
+    fn append_command(&mut self, command: Command, expired: bool) -> Result<()> {
+        let watched = if self.watchers.is_empty() { None } else { Some(command.clone()) };
+        let indexed = if self.indexes.is_empty() { None } else { Some(command.clone()) };
+        let command = self.store_blobs(command)?;
+        let (pos, len) = self.cur().append(&command)?;
+        self.metrics.appended(self.current_part, len);
+        self.seq += 1;
+
+        // readers only see the write once it's in the log, and see all of a batch at once
+        let (part, seq) = (self.current_part, self.seq);
+        let hint = &mut self.hint;
+        let superseded = self.shared.update_index(|index| {
+            let mut superseded = vec![];
+            for (op, len) in command.ops(len) {
+                hint.add(&op, pos, len);
+                let old = match op {
+                    Command::Set{key,value: _,expires} | Command::Blob{key,id: _,expires} => {
+                        index.insert(key, Entry { seq, expires, ..Entry::new(part, pos, len) })
+                    },
+                    Command::Remove{key} => index.remove(&key),
+                    Command::Merge{key,operand: _operand} => {
+                        let mut entry = Entry { seq, ..Entry::new(part, pos, len) };
+                        if let Some(old) = index.remove(&key) {
+                            entry.older = old.into_chain();
+                        }
+                        index.insert(key, entry);
+                        None
+                    },
+                    Command::Batch{ops: _} => None,
+                };
+                superseded.push(old);
+            }
+            superseded
+        });
+        for old in superseded {
+            if let Some(old) = old {
+                self.metrics.superseded(&old);
+            }
+            self.metrics.entries += 1;
+        }
+
+        if let Some(command) = indexed {
+            self.update_indexes(command)?;
+        }
+        self.written(len)?;
+        if let Some(command) = watched {
+            self.watchers.notify(command, self.seq, expired);
+        }
+        self.rotate_if_needed()?;
         self.compact_if_needed()?;
 
         Ok(())
     }
     
-    pub fn get_offset(&mut self, key: String) -> Result<Option<(Id,Offset)>> {
-        Ok(self.store.get(&key).map(|v| v.to_owned()))
-    }
-
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.get_offset(key)
-            .and_then(
-                |offset| offset.map_or(Ok(None), 
-                    |(id,offset)| {
-                        match self.read_offset(id, offset)? {
-                            Command::Set {key: _key, value} => Ok(Some(value)),
-                            Command::Remove {key: _key} => Ok(None),
-                        }
-                    }))
+    // moves values over the blob threshold out of the log
+    fn store_blobs(&mut self, command: Command) -> Result<Command> {
+        let threshold = match self.params.blob_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(command),
+        };
+
+        match command {
+            Command::Set{key,value,expires} if value.len() > threshold => {
+                let id = self.next_blob;
+                self.next_blob += 1;
+                // the log can only refer to a blob once it's as durable as the log is
+                self.blobs.write(id, &value, self.params.durability != Durability::Os)?;
+                Ok(Command::Blob{key, id, expires})
+            },
+            Command::Batch{ops} => {
+                let ops = ops.into_iter()
+                    .map(|op| self.store_blobs(op))
+                    .collect::<Result<Vec<Command>>>()?;
+                Ok(Command::Batch{ops})
+            },
+            command => Ok(command),
+        }
     }
 
-    pub fn read_offset(&mut self, id: Id, offset: Offset) -> Result<Command> {
-        self.part_mut(id).expect("error").read_offset(offset)
+    // removes blob files that no key refers to any more, including those left behind by a write
+    // that didn't make it into the log. nothing is removed while a snapshot is open, since it may
+    // still read blobs the store has moved on from. returns the number of blobs removed.
+    pub fn collect_blobs(&mut self) -> Result<usize> {
+        self.wait_compaction()?;
+        if self.retention.is_pinned() {
+            return Ok(0);
+        }
+
+        let mut live = BTreeSet::new();
+        let index = self.shared.index();
+        for (key, entry) in index.iter() {
+            for e in entry.older.iter().chain(std::iter::once(entry)) {
+                if let Some(Command::Blob{key: _key,id,expires: _expires}) = self.read_offset(e.part, e.offset)?.find(key) {
+                    live.insert(id);
+                }
+            }
+        }
+
+        // the newest blob is always kept, so that its id isn't handed out again on the next open
+        let mut ids = self.blobs.find()?;
+        ids.pop();
+        let mut removed = 0;
+        for id in ids {
+            if !live.contains(&id) {
+                self.blobs.remove(id)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn update_indexes(&mut self, command: Command) -> Result<()> {
+        for (op, _) in command.ops(0) {
+            let (key, value) = match op {
+                Command::Set{key,value,expires: _expires} => (key, Some(value)),
+                Command::Remove{key} => (key, None),
+                Command::Merge{key,operand: _operand} => {
+                    let value = self.get_bytes(&key)?;
+                    (key, value)
+                },
+                Command::Blob{key: _,id: _,expires: _} | Command::Batch{ops: _} => continue,
+            };
+            self.indexes.update(&key, value.as_ref().map(|v| &v[..]));
+        }
+        Ok(())
+    }
+
+    // reads every value to work out the secondary indexes from scratch
+    fn rebuild_indexes(&mut self) -> Result<()> {
+        self.indexes.clear();
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+
+        let view = self.shared.view();
+        let now = cmd::now();
+        for (key, entry) in view.index.iter().filter(|(_, e)| !e.is_expired(now)) {
+            let value = self.shared.read_value(&view.kvdbs, key, entry)?;
+            self.indexes.update(key, value.as_ref().map(|v| &v[..]));
+        }
+        Ok(())
+    }
+
+    // keys whose value has the indexed field equal to value
+    pub fn find_by(&self, index: &str, value: &serde_json::Value) -> Result<Vec<Vec<u8>>> {
+        self.find_range(index, (Bound::Included(value), Bound::Included(value)))
+    }
+
+    // keys whose value has the indexed field in range, ordered by the field. values that don't
+    // have the field, or aren't json, are left out.
+    pub fn find_range<'a, R: RangeBounds<&'a serde_json::Value>>(&self, index: &str, range: R) -> Result<Vec<Vec<u8>>> {
+        let keys = self.indexes.range(index, range)?;
+        // keys that have expired stay in the index until they're swept
+        Ok(keys.into_iter().filter(|key| self.entry(key).is_some()).collect())
+    }
+
+    pub fn get_offset(&self, key: &[u8]) -> Result<Option<(Id,Offset)>> {
+        Ok(self.entry(key).map(|e| (e.part, e.offset)))
+    }
+
+    // values that aren't valid utf-8 are reported as Utf8Error. use get_bytes for binary values.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?.map(into_string).transpose()
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.shared.get_bytes(key)
+    }
+
+    // the end of the log, where the next write will go
+    pub fn position(&self) -> Result<Position> {
+        Ok(Position::new(self.current_part, self.parts.size(self.current_part)?))
+    }
+
+    // reads the commands written after position, including those from other handles on the store.
+    // Position::start() reads the whole of the log that's still live.
+    pub fn tail(&self, position: Position) -> Tail {
+        Tail::new(self.parts.clone(), self.codec.clone(), position)
+    }
+
+    // key/value pairs with keys in range, in order. reverse it with rev().
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan<'_> {
+        self.resume(Cursor::new(range))
+    }
+
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Scan<'_> {
+        self.resume(Cursor::prefix(prefix))
+    }
+
+    // carries on a scan from where an earlier one stopped
+    pub fn resume(&self, cursor: Cursor) -> Scan<'_> {
+        Scan::new(&self.shared, self.shared.view(), cursor)
+    }
+
+    pub fn read_offset(&self, id: Id, offset: Offset) -> Result<Command> {
+        self.shared.read_offset(id, offset)
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.store.remove(&key).is_none() {
-            Err(KvsErrorKind::NotFound(key))?;
-        } else {
-            self.cur_mut().append(Command::Remove{key})?;
-            self.metrics.entries += 1;
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.before_write()?;
+
+        if self.entry(key).is_none() {
+            Err(KvsErrorKind::NotFound(String::from_utf8_lossy(key).into_owned()))?;
         }
-        
-        self.compact_if_needed()?;
 
+        self.append(Command::Remove{key: key.to_vec()})
+    }
+
+    // syncs a write according to the durability setting, before it's acknowledged
+    fn written(&mut self, len: u64) -> Result<()> {
+        self.unsynced_bytes += len;
+
+        if let Some(e) = self.syncer.as_ref().and_then(|s| s.take_error()) {
+            Err(KvsErrorKind::Io(e))?;
+        }
+
+        match self.params.durability {
+            Durability::Always => self.sync(),
+            Durability::GroupCommit { window, max_bytes } => {
+                if self.unsynced_bytes >= max_bytes || self.last_sync.elapsed() >= window {
+                    self.sync()
+                } else {
+                    Ok(())
+                }
+            },
+            Durability::Periodic(_) | Durability::Os => Ok(()),
+        }
+    }
+
+    // hands buffered writes to the OS without waiting for them to reach the disk
+    pub fn flush(&mut self) -> Result<()> {
+        self.cur().flush()
+    }
+
+    // waits until every write so far is on disk
+    pub fn sync(&mut self) -> Result<()> {
+        let cur = self.cur();
+        cur.flush()?;
+        cur.sync()?;
+        self.unsynced_bytes = 0;
+        self.last_sync = Instant::now();
         Ok(())
     }
 
     pub fn new(dir: &Path) -> Result<KvStore> {
+        KvStore::new_with_params(dir, KvStoreParams::new())
+    }
+
+    pub fn new_with_params(dir: &Path, params: KvStoreParams) -> Result<KvStore> {
         if !fs::metadata(dir).map_err(|e| KvsErrorKind::Io(e))?.is_dir() {
             return Err(KvsErrorKind::Config(format!("not a directory: {:?}", dir)))?;
         }
 
-        let parts = Parts::new(dir);
+        let mut parts = Parts::new(dir);
+        let ids = parts.find()?;
+        parts.remove_orphans(&ids)?;
+        let codec = KvStore::load_codec(&mut parts, &params, &ids)?;
         let mut kvdbs = BTreeMap::new();
-        let mut max_id = None;
-        for id in parts.find()? {
+        let mut last_id = None;
+        for id in ids.iter().cloned() {
             let path = parts.path_for_id(id);
             let file = KvStore::open_file(&path)?;
-            let kvdb = KvDb::new(file)?;
+            let kvdb = KvDb::with_framing(id, file, codec.clone(), parts.is_framed(id))?;
             
-            kvdbs.insert(id, kvdb);
-            max_id = Some(id);
+            kvdbs.insert(id, Arc::new(kvdb));
+            last_id = Some(id);
         }
 
-        let current_id = if let Some(some_id) = max_id {
-            some_id
-        } else {
-            let (id,file) = parts.create()?;
-            let kvdb = KvDb::new(file)?;
-            kvdbs.insert(id, kvdb);
-            id
+        let mut live = ids;
+        let mut current_since = SystemTime::now();
+        // partitions from before records were framed are only read from, so writes start a new one
+        let current_id = match last_id.filter(|id| parts.is_framed(*id)) {
+            Some(some_id) => {
+                if let Ok(meta) = fs::metadata(parts.path_for_id(some_id)) {
+                    current_since = meta.created().or_else(|_| meta.modified()).unwrap_or(current_since);
+                }
+                some_id
+            },
+            None => {
+                let (id,file) = parts.create()?;
+                let kvdb = KvDb::new(id, file, codec.clone())?;
+                kvdbs.insert(id, Arc::new(kvdb));
+                live.push(id);
+                parts.set_live(&live)?;
+                id
+            },
         };
 
-        Ok(KvStore {
-            parts: parts,
+        let retention = Retention::new(parts.clone());
+        let blobs = Blobs::new(dir);
+        let next_blob = blobs.next_id()?;
+        let shared = Shared::new(kvdbs, blobs.clone(), params.merge_operator.clone());
+        let mut kvs = KvStore {
+            parts,
+            codec,
             current_part: current_id,
-            kvdbs: kvdbs,
-            store: BTreeMap::new(),
-            params: KvStoreParams::new(),
+            current_since,
+            live,
+            shared: Arc::new(shared),
+            retention,
+            compaction: None,
+            seq: 0,
+            last_sweep: Instant::now(),
+            watchers: Watchers::new(),
+            indexes: Indexes::new(&params.indexes),
+            blobs,
+            next_blob,
+            hint: Hint::new(),
+            unsynced_bytes: 0,
+            last_sync: Instant::now(),
+            syncer: None,
+            params,
             metrics: KvStoreMetrics::new(),
 
-        })
+        };
+
+        kvs.metrics.recovery = kvs.recover()?;
+
+        match kvs.params.durability {
+            Durability::Periodic(interval) | Durability::GroupCommit { window: interval, max_bytes: _ } => {
+                kvs.syncer = Some(Syncer::start(interval, kvs.cur().try_clone_file()?));
+            },
+            Durability::Always | Durability::Os => {},
+        }
+
+        Ok(kvs)
+    }
+
+    // only the current partition is ever appended to, so only it can end in a torn write.
+    // the partial record is moved to a .corrupt side file so nothing is lost for good.
+    fn recover(&mut self) -> Result<Option<RecoveryReport>> {
+        let id = self.current_part;
+        let cur = self.cur();
+        let offset = match cur.torn_tail()? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        let saved_to = self.parts.corrupt_path_for_id(id);
+        let tail = cur.read_tail(offset)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&saved_to)
+            .and_then(|mut f| f.write_all(&tail).and_then(|_| f.sync_all()))
+            .map_err(KvsErrorKind::Io)?;
+        cur.truncate(offset)?;
+
+        Ok(Some(RecoveryReport {
+            part: id,
+            offset,
+            bytes: tail.len() as u64,
+            saved_to,
+        }))
     }
 
     pub fn open(path: &Path) -> Result<KvStore> {
-        let mut kvs = KvStore::new(path)?;
+        KvStore::open_with_params(path, KvStoreParams::new())
+    }
+
+    pub fn open_with_params(path: &Path, params: KvStoreParams) -> Result<KvStore> {
+        let mut kvs = KvStore::new_with_params(path, params)?;
         kvs.load()?;
         Ok(kvs)
     }
 
-    pub fn load(&mut self) -> Result<()> {
-        let mut index = BTreeMap::new();
+    // the codec is fixed when a store is created and recorded in its format marker file.
+    // a store without one was written before records were framed, so its partitions hold json a record per line.
+    fn load_codec(parts: &mut Parts, params: &KvStoreParams, ids: &[Id]) -> Result<Arc<dyn Codec>> {
+        let format = match parts.read_format()? {
+            Some(format) => format,
+            None => {
+                let format = Format {
+                    codec: if ids.is_empty() { params.codec.name() } else { codec::DEFAULT_CODEC }.to_owned(),
+                    unframed: ids.iter().cloned().max().unwrap_or(0),
+                };
+                parts.write_format(&format)?;
+                format
+            },
+        };
+        parts.unframed = format.unframed;
+        let name = format.codec;
 
-        let mut metrics = KvStoreMetrics::new();
+        if name == params.codec.name() {
+            Ok(params.codec.clone())
+        } else {
+            codec::builtin(&name)
+                .ok_or_else(|| KvsErrorKind::Config(format!("unknown codec: {}", name)).into())
+        }
+    }
+
+    pub fn load(&mut self) -> Result<()> {
+        let mut loader = Loader::new();
 
-        for (id, kvdb) in self.kvdbs.iter_mut() {
-            let loader = Loader::new(*id, index);
-            let visitor = kvdb.visit(loader)?;
-            index = visitor.index;
-            metrics.entries += visitor.metrics.entries;
+        for id in self.live.clone() {
+            loader.part = id;
+            let hint_path = self.parts.hint_path_for_id(id);
+            if let Some(hint) = Hint::read(&hint_path, self.parts.size(id)?)? {
+                loader.hint(hint);
+            } else {
+                loader = self.part(id)?.visit(loader)?;
+                if id != self.current_part {
+                    self.write_hint(id)?;
+                }
+            }
         }
+        self.hint = self.cur().visit(HintBuilder { hint: Hint::new() })?.hint;
         
-        self.store = index;
-        self.metrics.entries = metrics.entries;
+        self.shared.set_index(loader.index);
+        self.metrics.entries = loader.metrics.entries;
+        self.metrics.parts = loader.metrics.parts;
+        self.rebuild_indexes()?;
 
         Ok(())
     }
 
     pub fn inefficiency(&self) -> u32 {
-        if self.store.len() == 0 {
+        let len = self.shared.len();
+        if len == 0 {
             0
         } else {
-            (self.metrics.entries / (self.store.len() as u64)) as u32
+            (self.metrics.entries / (len as u64)) as u32
         }
     }
 
+    pub fn needs_compaction(&self) -> bool {
+        let dead = match self.params.compact_dead_ratio {
+            Some(ratio) => self.metrics.dead_bytes() > 0 && self.metrics.dead_ratio() >= ratio,
+            None => false,
+        };
+        dead || self.inefficiency() > self.params.compact_garbage_threshold
+    }
+
     pub fn compact_if_needed(&mut self) -> Result<()> {
-        if self.inefficiency() > self.params.compact_garbage_threshold {
-            self.compact()?
+        if self.compaction.is_none() && self.needs_compaction() {
+            self.start_compaction()?;
         }
 
         Ok(())
     }
 
+    // compacts the store, waiting for the result
     pub fn compact(&mut self) -> Result<()> {
-        let (id,file) = self.parts.create()?;
-        let kvdb = KvDb::new(file)?;
-        let (index, kvdb) = self.copy(id, kvdb)?;
-        
-        for (id, _kvdb) in self.kvdbs.iter() {
-            self.parts.remove(*id)?;
+        self.wait_compaction()?;
+        self.start_compaction()?;
+        self.wait_compaction()
+    }
+
+    pub fn is_compacting(&self) -> bool {
+        self.compaction.is_some()
+    }
+
+    // starts rewriting the sealed partitions that are mostly garbage on a background thread, while
+    // writes carry on in the current partition. returns false if a compaction is already running
+    // or there's nothing to do.
+    pub fn start_compaction(&mut self) -> Result<bool> {
+        if self.compaction.is_some() {
+            return Ok(false);
         }
 
-        self.kvdbs = BTreeMap::new();
-        self.kvdbs.insert(id, kvdb);
-        self.store = index;
-        self.current_part = id;
+        if self.parts.size(self.current_part)? > 0 {
+            self.rotate()?;
+        }
 
-        self.rotate_if_needed()?;
+        let candidates: Vec<(Id,PartStats)> = self.live.iter()
+            .cloned()
+            .filter(|id| *id != self.current_part)
+            .map(|id| (id, self.metrics.parts.get(&id).cloned().unwrap_or_default()))
+            .collect();
+        if candidates.is_empty() {
+            return Ok(false);
+        }
+
+        let job = CompactionJob {
+            parts: self.parts.clone(),
+            codec: self.codec.clone(),
+            candidates,
+            index: self.shared.index(),
+            garbage_ratio: self.params.compact_part_garbage_ratio,
+            now: cmd::now(),
+            merge_operator: self.params.merge_operator.clone(),
+            cancel: Arc::new(AtomicBool::new(false)),
+        };
+        self.compaction = Some(Compaction::start(job));
+
+        Ok(true)
+    }
+
+    // blocks until the running compaction, if any, has finished and been applied
+    pub fn wait_compaction(&mut self) -> Result<()> {
+        if let Some(mut compaction) = self.compaction.take() {
+            let output = compaction.wait()?;
+            self.finish_compaction(output)?;
+        }
+
+        Ok(())
+    }
+
+    // stops the running compaction, if any, and throws away its output
+    pub fn cancel_compaction(&mut self) -> Result<()> {
+        if let Some(mut compaction) = self.compaction.take() {
+            compaction.cancel();
+            match compaction.wait() {
+                Ok(output) => {
+                    for run in output.runs {
+                        self.parts.remove(run.dest_part)?;
+                    }
+                },
+                Err(e) => match e.kind() {
+                    KvsErrorKind::Cancelled => {},
+                    _ => return Err(e),
+                },
+            }
+        }
 
         Ok(())
     }
 
+    // applies the output of a compaction if it has finished
+    pub fn poll_compaction(&mut self) -> Result<()> {
+        let output = match self.compaction.as_mut().and_then(|c| c.poll()) {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        self.compaction = None;
+
+        self.finish_compaction(output?)
+    }
+
+    // each new partition takes the place of the ones it replaces in the replay order.
+    // they only become visible once the manifest is swapped, so a crash part way through
+    // leaves the store as it was before compaction.
+    fn finish_compaction(&mut self, output: CompactionOutput) -> Result<()> {
+        if output.runs.is_empty() {
+            return Ok(());
+        }
+
+        let mut live = self.live.clone();
+        for run in output.runs.iter() {
+            let pos = live.iter()
+                .position(|id| run.sources.contains(id))
+                .unwrap_or(0);
+            live.retain(|id| !run.sources.contains(id));
+            live.insert(pos, run.dest_part);
+        }
+        self.parts.set_live(&live)?;
+        self.live = live;
+
+        for run in output.runs {
+            let CompactedRun { sources, dest_part, dest, moved, expired, collapsed, entries, records } = run;
+            // readers can only be pointed at the new partition once it's open
+            self.shared.insert_part(dest_part, dest);
+
+            // everything copied was live when the compaction started
+            let size = self.parts.size(dest_part)?;
+            let mut stats = PartStats { live_bytes: size, total_bytes: size, records };
+
+            let metrics = &mut self.metrics;
+            self.shared.update_index(|index| {
+                // keys written since then already point somewhere newer
+                for (key, from, to) in moved.iter() {
+                    let relocated = index.get_mut(key)
+                        .map(|e| e.relocate(from, to)) == Some(true);
+                    if !relocated {
+                        stats.live_bytes -= to.len;
+                    }
+                }
+
+                // merges collapsed into a set. any further merges since then apply on top of it.
+                for (key, chain, to) in collapsed.iter() {
+                    let collapsed = index.get_mut(key)
+                        .map(|e| e.collapse(chain, to)) == Some(true);
+                    if collapsed {
+                        for e in chain.iter() {
+                            metrics.superseded(e);
+                        }
+                    } else {
+                        stats.live_bytes -= to.len;
+                    }
+                }
+
+                // expired values that weren't copied can't be read any more
+                for (key, from) in expired.iter() {
+                    let gone = match index.get_mut(key) {
+                        Some(entry) if entry.is_at(from) => true,
+                        Some(entry) => {
+                            entry.older.retain(|e| !e.is_at(from));
+                            false
+                        },
+                        None => false,
+                    };
+                    if gone {
+                        index.remove(key);
+                    }
+                }
+            });
+            self.metrics.parts.insert(dest_part, stats);
+            let copied = (moved.len() + collapsed.len()) as u64;
+            self.metrics.entries = (self.metrics.entries + copied).saturating_sub(entries);
+
+            for id in sources {
+                self.shared.remove_part(id);
+                self.metrics.parts.remove(&id);
+                self.retention.retire(id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn current_part_info(&self) -> PartInfo {
+        let stats = self.metrics.parts.get(&self.current_part).cloned().unwrap_or_default();
+        PartInfo {
+            id: self.current_part,
+            size: stats.total_bytes,
+            records: stats.records,
+            age: self.current_since.elapsed().unwrap_or_default(),
+        }
+    }
+
     pub fn rotate_if_needed(&mut self) -> Result<()> {
-        if self.parts.size(self.current_part)? > self.params.max_part_size {
+        let info = self.current_part_info();
+        if info.size > self.params.max_part_size || self.params.rotation.iter().any(|p| p.should_rotate(&info)) {
             self.rotate()?;
         }
 
@@ -261,32 +1215,40 @@ impl KvStore {
     }
 
     pub fn rotate(&mut self) -> Result<()> {
+        let sealed = self.current_part;
+        // sealed partitions are always synced, so sync() only ever has the current one to deal with
+        self.sync()?;
+        // the hint has followed every write, so the partition doesn't need reading back
+        let mut hint = std::mem::take(&mut self.hint);
+        hint.part_size = self.parts.size(sealed)?;
+        hint.write(&self.parts.hint_path_for_id(sealed))?;
+
         let (id,file) = self.parts.create()?;
-        let kvdb = KvDb::new(file)?;
-        self.kvdbs.insert(id, kvdb);
+        let kvdb = KvDb::new(id, file, self.codec.clone())?;
+        self.shared.insert_part(id, kvdb);
         self.current_part = id;
+        self.current_since = SystemTime::now();
+        self.unsynced_bytes = 0;
+        if let Some(ref syncer) = self.syncer {
+            syncer.set_file(self.cur().try_clone_file()?);
+        }
+
+        self.live.push(id);
+        self.parts.set_live(&self.live)?;
+
         Ok(())
     }
     
-    pub fn copy(&mut self, dest_part: Id, dest: KvDb) -> Result<(OffsetIndex, KvDb)> {
-        let mut index = Some(self.store.clone());
-        let mut dest = Some(dest);
-
-        for (id, kvdb) in self.kvdbs.iter_mut() {
-            let copy_visitor = CopyVisitor { 
-                src_part: *id, 
-                src_index: index.take().unwrap(), 
-                dest_part: dest_part,
-                dest: dest.take().unwrap()
-            };
-
-            let copy_visitor = kvdb.visit(copy_visitor)?;
+    // hints let load index a partition without decoding all of it, so they're written once it stops changing
+    pub fn write_hint(&mut self, id: Id) -> Result<()> {
+        KvStore::build_hint(&self.parts, id, &*self.part(id)?)
+    }
 
-            dest = Some(copy_visitor.dest);
-            index = Some(copy_visitor.src_index);
-        }
-        
-        Ok((index.unwrap(), dest.unwrap()))
+    pub(crate) fn build_hint(parts: &Parts, id: Id, kvdb: &KvDb) -> Result<()> {
+        let builder = HintBuilder { hint: Hint::new() };
+        let mut hint = kvdb.visit(builder)?.hint;
+        hint.part_size = parts.size(id)?;
+        hint.write(&parts.hint_path_for_id(id))
     }
 
     fn open_file(path: &Path) -> Result<File> {
@@ -301,25 +1263,14 @@ impl KvStore {
     }
 }
 
-struct CopyVisitor {
-    pub src_part: Id,
-    pub src_index: OffsetIndex,
-    pub dest_part: Id,
-    pub dest: KvDb,
-}
-
-impl Visitor for CopyVisitor {
-    fn command(&mut self, command: Command, pos: Offset) -> Result<bool> {
-        let key = match command {
-            Command::Set { ref key, value: ref _value } => key,
-            Command::Remove { ref key } => key
-        };
-        if let Some((src_id, src_pos)) = self.src_index.get_mut(key) {
-            if self.src_part == *src_id && pos == *src_pos {
-                *src_pos = self.dest.append(command)?;
-                *src_id = self.dest_part;
-            }
+impl Drop for KvStore {
+    // an unfinished compaction is abandoned. its partition isn't in the manifest yet,
+    // so it's cleaned up the next time the store is opened.
+    // writes that haven't been synced yet are, unless syncing is left to the OS.
+    fn drop(&mut self) {
+        if self.params.durability != Durability::Os {
+            let _ = self.sync();
         }
-        Ok(true)
+        let _ = self.cancel_compaction();
     }
 }
@@ -1,10 +1,12 @@
 use std::io::BufRead;
 use std::io::Result;
 
+// partitions written before records were framed hold one json record per line
 #[derive(Debug,PartialEq,PartialOrd)]
 pub struct Line {
     pub pos: u64,
-    pub text: String,
+    pub len: u64, // including the line ending
+    pub text: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -18,19 +20,19 @@ impl<B: BufRead> Iterator for Lines<B> {
 
     fn next(&mut self) -> Option<Result<Line>> {
         let pos = self.pos;
-        let mut buf = String::new();
-        
-        match self.buf.read_line(&mut buf) {
+        let mut buf = vec![];
+
+        match self.buf.read_until(b'\n', &mut buf) {
             Ok(0) => None,
-            Ok(_n) => {
-                self.pos += buf.len() as u64;
-                if buf.ends_with("\n") {
+            Ok(n) => {
+                self.pos += n as u64;
+                if buf.ends_with(b"\n") {
                     buf.pop();
-                    if buf.ends_with("\r") {
+                    if buf.ends_with(b"\r") {
                         buf.pop();
                     }
                 }
-                Some(Ok(Line { pos: pos, text: buf }))
+                Some(Ok(Line { pos, len: n as u64, text: buf }))
             }
             Err(e) => Some(Err(e))
         }
@@ -39,9 +41,14 @@ impl<B: BufRead> Iterator for Lines<B> {
 
 impl <B> Lines<B> {
     pub fn new(buf: B) -> Lines<B> {
+        Lines::starting_at(buf, 0)
+    }
+
+    // for a reader that's already been positioned at pos
+    pub fn starting_at(buf: B, pos: u64) -> Lines<B> {
         Lines {
-            pos: 0,
-            buf: buf,
+            pos,
+            buf,
         }
     }
 }
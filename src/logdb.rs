@@ -1,56 +1,287 @@
 use std::fs::File;
-use std::io::{BufRead,BufReader,Seek,SeekFrom,Write};
+use std::io::{self,BufReader,Read,Seek,SeekFrom,Write};
 
 use crate::result::*;
-use crate::lines::*;
+use crate::frames::{self,Frames,HEADER_LEN};
+use crate::lines::Lines;
+use crate::parts::Id;
 
 pub type Offset = u64;
 
+// most of a record that's read by offset before its length has been borne out by the file
+const MAX_PREALLOCATED: usize = 1 << 20;
+
 pub trait Visitor {
-    fn line(&mut self, line: String, offset: Offset) -> Result<bool>;
+    // len is the size of the whole record on disk, framing included
+    fn record(&mut self, record: Vec<u8>, offset: Offset, len: u64) -> Result<bool>;
 }
 
 pub struct LogDb {
+    id: Id,
     f: File,
+    framed: bool, // false for partitions written before records were framed, which hold a record per line
 }
 
 impl LogDb {
-    pub fn new(f: File) -> Result<LogDb> {
+    pub fn new(id: Id, f: File, framed: bool) -> Result<LogDb> {
         Ok(LogDb {
-            f: f,
+            id,
+            f,
+            framed,
         })
     }
 
-    pub fn visit<V: Visitor>(&mut self, mut visitor: V) -> Result<V> {
-        self.f.seek(SeekFrom::Start(0))
+    pub fn visit<V: Visitor>(&self, visitor: V) -> Result<V> {
+        self.visit_from(0, visitor)
+    }
+
+    // visits the records from the one starting at offset to the end of the log
+    pub fn visit_from<V: Visitor>(&self, offset: Offset, mut visitor: V) -> Result<V> {
+        (&self.f).seek(SeekFrom::Start(offset))
             .map_err(|e| KvsErrorKind::Io(e))?;
         let file = BufReader::new(&self.f);
-        for line in Lines::new(file) {
-            let l = line.map_err(|e| KvsErrorKind::Io(e))?;
-            if !visitor.line(l.text, l.pos)? {
+        if !self.framed {
+            for line in Lines::starting_at(file, offset) {
+                let l = line.map_err(KvsErrorKind::Io)?;
+                if !visitor.record(l.text, l.pos, l.len)? {
+                    break;
+                }
+            }
+            return Ok(visitor);
+        }
+
+        let mut records = Frames::starting_at(file, offset);
+        while let Some(record) = records.next() {
+            let r = record.map_err(|e| self.read_error(e, records.pos()))?;
+            let len = HEADER_LEN + r.data.len() as u64;
+            if !visitor.record(r.data, r.pos, len)? {
                 break;
             }
         }
         Ok(visitor)
     }
 
-    pub fn append(&mut self, record: String) -> Result<Offset> {
-        let pos = self.f.seek(SeekFrom::End(0))
-            .map_err(|e| KvsErrorKind::Io(e))?;
-        
-        writeln!(self.f, "{}", record)
+    // finds a partially written record at the end of the log, as left behind by a crash
+    // during append, and returns the offset where it starts.
+    // damage followed by further intact records is not a torn write and is reported as corruption,
+    // as is anything longer than the record it starts with. either means the partition isn't what
+    // it's thought to be. the first record is no different: a new partition's first write can be torn too.
+    pub fn torn_tail(&self) -> Result<Option<Offset>> {
+        // only framed records can be told apart from a torn write
+        if !self.framed {
+            return Ok(None);
+        }
+
+        (&self.f).seek(SeekFrom::Start(0))
+            .map_err(KvsErrorKind::Io)?;
+        let file = BufReader::new(&self.f);
+        let mut records = Frames::new(file);
+        while let Some(record) = records.next() {
+            if let Err(e) = record {
+                let pos = records.pos();
+                if !frames::is_corruption(&e) {
+                    return Err(KvsErrorKind::Io(e))?;
+                }
+                // a bad checksum is only a torn write if nothing follows the damaged record
+                return match records.next() {
+                    None if self.within_frame(pos)? => Ok(Some(pos)),
+                    _ => Err(KvsErrorKind::Corruption(self.id, pos))?,
+                };
+            }
+        }
+        Ok(None)
+    }
+
+    // whether the bytes from offset to the end fit in the record whose header is at offset
+    fn within_frame(&self, offset: Offset) -> Result<bool> {
+        Ok(self.shortfall(offset)?.is_some())
+    }
+
+    // whether the record at offset runs past the end of the log, as one still being appended does.
+    // a record that's all there but doesn't match its checksum isn't incomplete.
+    pub fn is_incomplete(&self, offset: Offset) -> Result<bool> {
+        Ok(self.framed && matches!(self.shortfall(offset)?, Some(n) if n > 0))
+    }
+
+    // how many bytes short of the record at offset the log is, or None if there's more after it
+    fn shortfall(&self, offset: Offset) -> Result<Option<u64>> {
+        let size = self.f.metadata()
+            .map_err(KvsErrorKind::Io)?
+            .len();
+        if size - offset < HEADER_LEN {
+            return Ok(Some(HEADER_LEN - (size - offset)));
+        }
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        read_at(&self.f, &mut header, offset)
+            .map_err(KvsErrorKind::Io)?;
+        let (len, _crc) = frames::parse_header(&header);
+        let frame = HEADER_LEN + len as u64;
+        Ok(frame.checked_sub(size - offset))
+    }
+
+    // raw bytes from offset to the end of the log
+    pub fn read_tail(&self, offset: Offset) -> Result<Vec<u8>> {
+        (&self.f).seek(SeekFrom::Start(offset))
+            .map_err(KvsErrorKind::Io)?;
+        let mut tail = vec![];
+        (&self.f).read_to_end(&mut tail)
+            .map_err(KvsErrorKind::Io)?;
+        Ok(tail)
+    }
+
+    pub fn truncate(&self, offset: Offset) -> Result<()> {
+        self.f.set_len(offset)
+            .map_err(KvsErrorKind::Io)?;
+        Ok(())
+    }
+
+    // writes at the end of the file without using its cursor, like reads. appends mustn't run
+    // concurrently with each other, but can with reads.
+    pub fn append(&self, record: &[u8]) -> Result<Offset> {
+        if !self.framed {
+            Err(KvsErrorKind::Config(format!("partition {} is read only", self.id)))?;
+        }
+
+        let pos = self.f.metadata()
+            .map_err(KvsErrorKind::Io)?
+            .len();
+
+        write_at(&self.f, &frames::encode(record), pos)
             .map_err(|e| KvsErrorKind::Io(e))?;
 
         Ok(pos)
     }
 
-    pub fn read_offset(&mut self, offset: Offset) -> Result<String> {
-        self.f.seek(SeekFrom::Start(offset))
-            .map_err(|e| KvsErrorKind::Io(e))?;
-        let mut file = BufReader::new(&self.f);
-        let mut line = String::new();
-        file.read_line(&mut line)
-            .map_err(|e| KvsErrorKind::Io(e))?;
-        Ok(line)
+    // hands anything buffered in the process to the OS
+    pub fn flush(&self) -> Result<()> {
+        (&self.f).flush()
+            .map_err(KvsErrorKind::Io)?;
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.f.sync_data()
+            .map_err(KvsErrorKind::Io)?;
+        Ok(())
     }
-}
\ No newline at end of file
+
+    // a second handle to the log file, for syncing it from another thread
+    pub fn try_clone_file(&self) -> Result<File> {
+        let f = self.f.try_clone()
+            .map_err(KvsErrorKind::Io)?;
+        Ok(f)
+    }
+
+    // reads without moving the file's cursor, so lookups don't need the log to themselves.
+    // the header is read onto the stack and only the payload is allocated.
+    pub fn read_offset(&self, offset: Offset) -> Result<Vec<u8>> {
+        if !self.framed {
+            let file = BufReader::new(ReadAt { f: &self.f, pos: offset });
+            return match Lines::starting_at(file, offset).next() {
+                Some(line) => Ok(line.map_err(KvsErrorKind::Io)?.text),
+                None => Err(KvsErrorKind::Corruption(self.id, offset))?,
+            };
+        }
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        read_at(&self.f, &mut header, offset)
+            .map_err(|e| self.read_error(e, offset))?;
+        let (len, crc) = frames::parse_header(&header);
+
+        // the buffer only grows past MAX_PREALLOCATED as the payload's actually read, so a corrupt
+        // length runs into the end of the file rather than into a huge allocation
+        let mut record = Vec::with_capacity((len as usize).min(MAX_PREALLOCATED));
+        let read = ReadAt { f: &self.f, pos: offset + HEADER_LEN }
+            .take(len as u64)
+            .read_to_end(&mut record)
+            .map_err(KvsErrorKind::Io)?;
+        if read < len as usize {
+            Err(KvsErrorKind::Corruption(self.id, offset))?;
+        }
+        frames::check(&record, crc)
+            .map_err(|e| self.read_error(e, offset))?;
+        Ok(record)
+    }
+
+    fn read_error(&self, e: io::Error, offset: Offset) -> KvsErrorKind {
+        if frames::is_corruption(&e) {
+            KvsErrorKind::Corruption(self.id, offset)
+        } else {
+            KvsErrorKind::Io(e)
+        }
+    }
+}
+
+// reads from the file at offset without using its cursor, returning how many bytes were read
+#[cfg(unix)]
+fn read_some_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    f.read_at(buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(f: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    f.write_all_at(buf, offset)
+}
+
+// seek_read and seek_write move the cursor on windows, but visits always seek before reading
+#[cfg(windows)]
+fn read_some_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    f.seek_read(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(f: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match f.seek_write(buf, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write record")),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// elsewhere there's only the cursor, so every positioned read and write takes turns on it
+#[cfg(not(any(unix, windows)))]
+static CURSOR: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(not(any(unix, windows)))]
+fn read_some_at(mut f: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let _cursor = CURSOR.lock().unwrap();
+    f.seek(SeekFrom::Start(offset))?;
+    f.read(buf)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn write_at(mut f: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    let _cursor = CURSOR.lock().unwrap();
+    f.seek(SeekFrom::Start(offset))?;
+    f.write_all(buf)
+}
+
+fn read_at(f: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    ReadAt { f, pos: offset }.read_exact(buf)
+}
+
+// reads from pos on without using the file's cursor
+struct ReadAt<'a> {
+    f: &'a File,
+    pos: u64,
+}
+
+impl <'a> Read for ReadAt<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = read_some_at(self.f, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,Write};
+use std::path::Path;
+
+use crate::result::*;
+use crate::parts::Id;
+
+const HEADER: &str = "kvs-manifest 1";
+
+// the partitions that make up the store, in the order they are replayed.
+// partition files not listed here are leftovers of an interrupted rotation or compaction.
+#[derive(Clone,PartialEq,Debug)]
+pub struct Manifest {
+    pub parts: Vec<Id>,
+}
+
+impl Manifest {
+    pub fn new(parts: Vec<Id>) -> Manifest {
+        Manifest {
+            parts,
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Option<Manifest>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(KvsErrorKind::Io(e))?,
+        };
+
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            Err(KvsErrorKind::Config(format!("invalid manifest: {:?}", path)))?;
+        }
+
+        let mut parts = vec![];
+        for line in lines {
+            let id = line.trim().parse::<Id>()
+                .map_err(KvsErrorKind::ParseIntError)?;
+            parts.push(id);
+        }
+
+        Ok(Some(Manifest::new(parts)))
+    }
+
+    // after a crash either the old or the new list of partitions is in effect
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut text = String::from(HEADER);
+        text.push('\n');
+        for id in self.parts.iter() {
+            text.push_str(&format!("{}\n", id));
+        }
+        replace_file(path, text.as_bytes())
+    }
+}
+
+// the new contents are synced to a temporary file and renamed over the old file,
+// so a reader sees one or the other and never part of either
+pub fn replace_file(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)
+        .and_then(|mut f| f.write_all(data).and_then(|_| f.sync_all()))
+        .map_err(KvsErrorKind::Io)?;
+    fs::rename(&tmp, path)
+        .map_err(KvsErrorKind::Io)?;
+
+    // persist the rename itself. directories can't be opened for syncing on every platform
+    if let Some(dir) = path.parent() {
+        if let Ok(d) = File::open(dir) {
+            let _ = d.sync_all();
+        }
+    }
+
+    Ok(())
+}
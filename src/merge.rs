@@ -0,0 +1,110 @@
+use std::fmt::Debug;
+
+use crate::result::*;
+use crate::command::{self,Command};
+
+// combines merge operands with a key's value. operands are applied in the order they were written,
+// and applying them in several steps has to give the same result as applying them all at once,
+// since compaction collapses them as it goes.
+pub trait MergeOperator: Debug + Send + Sync {
+    fn name(&self) -> &str;
+    // existing is None if the key is missing
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Result<Vec<u8>>;
+}
+
+// adds integers written as decimal text, so counters read back with get like any other value
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub struct AddI64;
+
+impl AddI64 {
+    fn parse(key: &[u8], value: &[u8]) -> Result<i64> {
+        let n = std::str::from_utf8(value)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| KvsErrorKind::Merge(format!("not an integer: {} for key {}",
+                String::from_utf8_lossy(value), String::from_utf8_lossy(key))))?;
+        Ok(n)
+    }
+}
+
+impl MergeOperator for AddI64 {
+    fn name(&self) -> &str {
+        "add_i64"
+    }
+
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let mut total = match existing {
+            Some(value) => AddI64::parse(key, value)?,
+            None => 0,
+        };
+        for operand in operands {
+            total = total.wrapping_add(AddI64::parse(key, operand)?);
+        }
+        Ok(total.to_string().into_bytes())
+    }
+}
+
+// appends operands to the value, with separator between each of them
+#[derive(Clone,PartialEq,Debug,Default)]
+pub struct Append {
+    pub separator: Vec<u8>,
+}
+
+impl Append {
+    pub fn new() -> Append {
+        Append::default()
+    }
+
+    pub fn with_separator(separator: &[u8]) -> Append {
+        Append { separator: separator.to_vec() }
+    }
+}
+
+impl MergeOperator for Append {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let mut value = existing.map(|v| v.to_vec());
+        for operand in operands {
+            match value {
+                Some(ref mut v) => {
+                    v.extend_from_slice(&self.separator);
+                    v.extend_from_slice(operand);
+                },
+                None => value = Some(operand.to_vec()),
+            }
+        }
+        Ok(value.unwrap_or_default())
+    }
+}
+
+// works out a key's value from the records that make it up, oldest first:
+// the set it started from, if any, and the merges since
+pub fn fold(operator: Option<&dyn MergeOperator>, key: &[u8], records: Vec<Command>, now: u64) -> Result<Option<Vec<u8>>> {
+    let mut value = None;
+    let mut operands = vec![];
+    for record in records {
+        match record {
+            Command::Set { key: _, value: v, expires } => {
+                value = if command::is_expired(expires, now) { None } else { Some(v) };
+                operands.clear();
+            },
+            Command::Remove { key: _ } => {
+                value = None;
+                operands.clear();
+            },
+            Command::Merge { key: _, operand } => operands.push(operand),
+            Command::Blob { key: _, id, expires: _ } => Err(KvsErrorKind::Codec(format!("unresolved blob {}", id)))?,
+            Command::Batch { ops: _ } => Err(KvsErrorKind::Codec("nested batch".to_owned()))?,
+        }
+    }
+
+    if operands.is_empty() {
+        return Ok(value);
+    }
+    let operator = operator
+        .ok_or_else(|| KvsErrorKind::Config("no merge operator configured".to_owned()))?;
+    Ok(Some(operator.merge(key, value.as_ref().map(|v| &v[..]), &operands)?))
+}
@@ -1,15 +1,58 @@
 use std::path::{Path,PathBuf};
 use std::fs::{self,File,OpenOptions};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
 
 use crate::result::*;
 use crate::globber::*;
+use crate::manifest::{self,Manifest};
 
 pub type Id = usize;
 
+// what the FORMAT marker records: the codec the records are encoded with, and for a store
+// written before records were framed, the last partition holding a record per line
+#[derive(Clone,PartialEq,Debug)]
+pub struct Format {
+    pub codec: String,
+    pub unframed: Id, // 0 if every partition is framed
+}
+
+impl Format {
+    fn parse(text: &str) -> Result<Format> {
+        let mut lines = text.lines().map(|l| l.trim());
+        let mut format = Format {
+            codec: lines.next().unwrap_or("").to_owned(),
+            unframed: 0,
+        };
+        for line in lines.filter(|l| !l.is_empty()) {
+            match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                ["unframed", id] => {
+                    format.unframed = id.parse::<Id>()
+                        .map_err(KvsErrorKind::ParseIntError)?;
+                },
+                _ => Err(KvsErrorKind::Config(format!("invalid format marker: {:?}", line)))?,
+            }
+        }
+        Ok(format)
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!("{}\n", self.codec);
+        if self.unframed > 0 {
+            text.push_str(&format!("unframed {}\n", self.unframed));
+        }
+        text
+    }
+}
+
+#[derive(Clone,Debug)]
 pub struct Parts {
     pub dir: PathBuf,
     pub ext: String,
     pub globber: Globber,
+    pub unframed: Id, // partitions up to this one hold a record per line, as recorded in the format marker
+    next_id: Arc<AtomicUsize>, // shared by every clone, so a compaction and the writer never create the same partition
 }
 
 impl Parts {
@@ -20,6 +63,8 @@ impl Parts {
             dir: dir.to_owned(),
             ext: ext.to_owned(),
             globber: Globber { pattern: pattern.to_str().unwrap().to_owned() },
+            unframed: 0,
+            next_id: Arc::new(AtomicUsize::new(0)),
         }
     }
 
@@ -30,7 +75,7 @@ impl Parts {
     }
 
     pub fn create(&self) -> Result<(Id,File)> {
-        let id = self.next_id()?;
+        let id = self.allocate_id()?;
 
         let path = self.path_for_id(id);
 
@@ -49,11 +94,29 @@ impl Parts {
 
         fs::remove_file(path)
             .map_err(|e| KvsErrorKind::Io(e))?;
+
+        match fs::remove_file(self.hint_path_for_id(id)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            r => r.map_err(KvsErrorKind::Io)?,
+        }
         
         Ok(())
     }
 
+    // live partitions according to the manifest. stores from before the manifest existed
+    // get one listing every partition file in the directory.
     pub fn find(&self) -> Result<Vec<Id>> {
+        if let Some(manifest) = Manifest::read(&self.manifest_path())? {
+            return Ok(manifest.parts);
+        }
+
+        let result = self.find_files()?;
+        self.set_live(&result)?;
+
+        Ok(result)
+    }
+
+    pub fn find_files(&self) -> Result<Vec<Id>> {
         let mut result = vec![];
 
         for path in self.globber.find()? {
@@ -65,6 +128,21 @@ impl Parts {
         Ok(result)
     }
 
+    pub fn set_live(&self, ids: &[Id]) -> Result<()> {
+        Manifest::new(ids.to_vec()).write(&self.manifest_path())
+    }
+
+    // removes partition files that didn't make it into the manifest
+    pub fn remove_orphans(&self, live: &[Id]) -> Result<()> {
+        for id in self.find_files()? {
+            if !live.contains(&id) {
+                self.remove(id)?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn open(&self, id: Id) -> Result<File> {
         let path = self.path_for_id(id);
         let f = OpenOptions::new()
@@ -75,11 +153,46 @@ impl Parts {
         Ok(f)
     }
 
+    pub fn manifest_path(&self) -> PathBuf {
+        self.dir.join("MANIFEST")
+    }
+
+    pub fn format_path(&self) -> PathBuf {
+        self.dir.join("FORMAT")
+    }
+
+    pub fn read_format(&self) -> Result<Option<Format>> {
+        match fs::read_to_string(self.format_path()) {
+            Ok(s) => Ok(Some(Format::parse(&s)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(KvsErrorKind::Io(e))?,
+        }
+    }
+
+    // written the same way as the manifest, as a store can't be opened without it
+    pub fn write_format(&self, format: &Format) -> Result<()> {
+        manifest::replace_file(&self.format_path(), format.to_text().as_bytes())
+    }
+
+    // whether the partition's records are framed rather than a line each
+    pub fn is_framed(&self, id: Id) -> bool {
+        id > self.unframed
+    }
+
     pub fn path_for_id(&self, id: Id) -> PathBuf {
         let name = PathBuf::from(format!("{}.{}", id, self.ext));
         self.dir.join(name)
     }
 
+    pub fn hint_path_for_id(&self, id: Id) -> PathBuf {
+        self.dir.join(format!("{}.hint", id))
+    }
+
+    // side file holding bytes cut from a partition during recovery
+    pub fn corrupt_path_for_id(&self, id: Id) -> PathBuf {
+        self.dir.join(format!("{}.corrupt", id))
+    }
+
     pub fn id_for_path(&self, path: &Path) -> Result<Id> {
         let name = path.file_stem()
             .ok_or_else(|| KvsErrorKind::GlobError(format!("error parsing file name {:?}", path.to_str())))?;
@@ -88,6 +201,19 @@ impl Parts {
         Ok(id)
     }
 
+    // ids are never handed out twice, and stay ahead of any partition file in the directory
+    fn allocate_id(&self) -> Result<Id> {
+        let on_disk = self.next_id()?;
+        let mut next = self.next_id.load(Ordering::SeqCst);
+        loop {
+            let id = std::cmp::max(next, on_disk);
+            match self.next_id.compare_exchange(next, id + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Ok(id),
+                Err(current) => next = current,
+            }
+        }
+    }
+
     pub fn next_id(&self) -> Result<Id> {
         let paths = self.globber.find()?;
         let mut max_id = 0;
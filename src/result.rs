@@ -13,6 +13,9 @@ pub enum KvsErrorKind {
     #[fail(display = "{}", _0)]
     ParserError(#[cause] serde_json::error::Error),
 
+    #[fail(display = "Codec error: {}", _0)]
+    Codec(String),
+
     #[fail(display = "Not Found: {}", _0)]
     NotFound(String),
 
@@ -27,6 +30,24 @@ pub enum KvsErrorKind {
 
     #[fail(display = "{}", _0)]
     InvalidPartition(usize),
+
+    #[fail(display = "Compaction failed: {}", _0)]
+    Compaction(String),
+
+    #[fail(display = "Compaction cancelled")]
+    Cancelled,
+
+    #[fail(display = "Corrupt record in partition {} at offset {}", _0, _1)]
+    Corruption(usize, u64),
+
+    #[fail(display = "Transaction conflict on key: {}", _0)]
+    Conflict(String),
+
+    #[fail(display = "Merge failed: {}", _0)]
+    Merge(String),
+
+    #[fail(display = "History at partition {} offset {} is no longer available", _0, _1)]
+    HistoryCompacted(usize, u64),
 }
 
 #[derive(Debug)]
@@ -68,4 +89,9 @@ impl Display for KvsError {
     }
 }
 
-pub type Result<T> = std::result::Result<T,KvsError>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T,KvsError>;
+// a value as a string, reporting where it stops being valid utf-8 if it isn't
+pub(crate) fn into_string(value: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(value)
+        .map_err(|e| KvsErrorKind::Utf8Error(e.utf8_error().valid_up_to()))?)
+}
@@ -0,0 +1,46 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::parts::Id;
+
+// the partition currently being appended to
+#[derive(Clone,PartialEq,Debug)]
+pub struct PartInfo {
+    pub id: Id,
+    pub size: u64, // bytes
+    pub records: u64,
+    pub age: Duration, // time since the partition was created
+}
+
+// decides when the current partition is sealed and a new one started.
+// checked after every append.
+pub trait RotationPolicy: Debug + Send + Sync {
+    fn should_rotate(&self, part: &PartInfo) -> bool;
+}
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub struct MaxSize(pub u64);
+
+impl RotationPolicy for MaxSize {
+    fn should_rotate(&self, part: &PartInfo) -> bool {
+        part.size > self.0
+    }
+}
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub struct MaxRecords(pub u64);
+
+impl RotationPolicy for MaxRecords {
+    fn should_rotate(&self, part: &PartInfo) -> bool {
+        part.records >= self.0
+    }
+}
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub struct MaxAge(pub Duration);
+
+impl RotationPolicy for MaxAge {
+    fn should_rotate(&self, part: &PartInfo) -> bool {
+        part.records > 0 && part.age >= self.0
+    }
+}
@@ -0,0 +1,143 @@
+use im::ordmap::Iter;
+use std::ops::{Bound,RangeBounds};
+
+use crate::result::*;
+use crate::command;
+use crate::shared::{Shared,View};
+use crate::Entry;
+
+// where a scan got to, so it can be picked up again later with KvStore::resume.
+// holds the part of the range that hasn't been returned yet from either end.
+#[derive(Clone,PartialEq,Debug)]
+pub struct Cursor {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl Cursor {
+    pub fn new<R: RangeBounds<Vec<u8>>>(range: R) -> Cursor {
+        Cursor {
+            start: owned(range.start_bound()),
+            end: owned(range.end_bound()),
+        }
+    }
+
+    // every key that starts with prefix
+    pub fn prefix(prefix: &[u8]) -> Cursor {
+        Cursor {
+            start: Bound::Included(prefix.to_vec()),
+            end: match successor(prefix) {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            },
+        }
+    }
+
+    // ranges like these are empty, but a map's range can panic on them rather than returning nothing
+    pub(crate) fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start), Bound::Excluded(end)) |
+            (Bound::Excluded(start), Bound::Included(end)) |
+            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn bounds(&self) -> (Bound<Vec<u8>>,Bound<Vec<u8>>) {
+        (self.start.clone(), self.end.clone())
+    }
+}
+
+fn owned(bound: Bound<&Vec<u8>>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_owned()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_owned()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// the first key after every key starting with prefix, None if there isn't one
+fn successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut key = prefix.to_vec();
+    while let Some(last) = key.pop() {
+        if last < 0xff {
+            key.push(last + 1);
+            return Some(key);
+        }
+    }
+    None
+}
+
+// key/value pairs in key order, from either end. values are only read from the log as
+// the scan reaches them, from the store as it was when the scan started.
+pub struct Scan<'a> {
+    store: &'a Shared,
+    view: View,
+    cursor: Cursor,
+}
+
+impl <'a> Scan<'a> {
+    pub(crate) fn new(store: &'a Shared, view: View, cursor: Cursor) -> Scan<'a> {
+        Scan {
+            store,
+            view,
+            cursor,
+        }
+    }
+
+    // the rest of the scan, for resuming it after this one is dropped
+    pub fn cursor(&self) -> Cursor {
+        self.cursor.clone()
+    }
+
+    fn range(&self) -> Option<Iter<'_,Vec<u8>,Entry>> {
+        if self.cursor.is_empty() {
+            None
+        } else {
+            Some(self.view.index.range(self.cursor.bounds()))
+        }
+    }
+
+    fn read(&self, key: Vec<u8>) -> Option<Result<(Vec<u8>,Vec<u8>)>> {
+        let entry = &self.view.index[&key];
+        if entry.is_expired(command::now()) {
+            return None;
+        }
+        match self.store.read_value(&self.view.kvdbs, &key, entry) {
+            Ok(Some(value)) => Some(Ok((key, value))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl <'a> Iterator for Scan<'a> {
+    type Item = Result<(Vec<u8>,Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.range()?
+                .next()
+                .map(|(key, _)| key.to_owned())?;
+            self.cursor.start = Bound::Excluded(key.clone());
+            if let Some(item) = self.read(key) {
+                return Some(item);
+            }
+        }
+    }
+}
+
+impl <'a> DoubleEndedIterator for Scan<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.range()?
+                .next_back()
+                .map(|(key, _)| key.to_owned())?;
+            self.cursor.end = Bound::Excluded(key.clone());
+            if let Some(item) = self.read(key) {
+                return Some(item);
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap,BTreeSet};
+use std::ops::{Bound,RangeBounds};
+
+use serde_json::Value;
+
+use crate::result::*;
+use crate::scan::Cursor;
+
+// a field of json values to look keys up by, given as a path like $.email or $.tags[0]
+#[derive(Clone,PartialEq,Debug)]
+pub struct IndexDef {
+    pub name: String,
+    pub path: String,
+    pointer: String, // the path as a json pointer
+}
+
+impl IndexDef {
+    pub fn new(name: &str, path: &str) -> Result<IndexDef> {
+        Ok(IndexDef {
+            name: name.to_owned(),
+            path: path.to_owned(),
+            pointer: pointer(path)?,
+        })
+    }
+
+    fn extract(&self, doc: &Value) -> Option<Vec<u8>> {
+        doc.pointer(&self.pointer).and_then(encode)
+    }
+}
+
+// turns $.a.b[2] into /a/b/2
+fn pointer(path: &str) -> Result<String> {
+    let invalid = || KvsErrorKind::Config(format!("invalid json path: {}", path));
+    if !path.starts_with('$') {
+        Err(invalid())?;
+    }
+
+    let mut pointer = String::new();
+    let mut rest = &path[1..];
+    while !rest.is_empty() {
+        let (segment, next) = if let Some(field) = rest.strip_prefix('.') {
+            let end = field.find(['.', '[']).unwrap_or(field.len());
+            (&field[..end], &field[end..])
+        } else if let Some(index) = rest.strip_prefix('[') {
+            let end = index.find(']').ok_or_else(invalid)?;
+            if index[..end].parse::<usize>().is_err() {
+                Err(invalid())?;
+            }
+            (&index[..end], &index[end + 1..])
+        } else {
+            Err(invalid())?
+        };
+        if segment.is_empty() {
+            Err(invalid())?;
+        }
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+        rest = next;
+    }
+
+    Ok(pointer)
+}
+
+// an encoding of json scalars whose byte order matches their order:
+// null, then false and true, then numbers, then strings. arrays and objects aren't indexed.
+fn encode(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Null => Some(vec![0]),
+        Value::Bool(b) => Some(vec![1, *b as u8]),
+        Value::Number(n) => {
+            let f = n.as_f64()?;
+            let f = if f == 0.0 { 0.0 } else { f }; // -0 and 0 are equal
+            let bits = f.to_bits();
+            let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+            let mut encoded = vec![2];
+            encoded.extend_from_slice(&bits.to_be_bytes());
+            Some(encoded)
+        },
+        Value::String(s) => {
+            let mut encoded = vec![3];
+            encoded.extend_from_slice(s.as_bytes());
+            Some(encoded)
+        },
+        Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+fn encode_bound(bound: Bound<&&Value>) -> Result<Bound<Vec<u8>>> {
+    let encoded = |value: &Value| encode(value)
+        .ok_or_else(|| KvsErrorKind::Config(format!("can't look up {} in an index", value)));
+    Ok(match bound {
+        Bound::Included(value) => Bound::Included(encoded(value)?),
+        Bound::Excluded(value) => Bound::Excluded(encoded(value)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+#[derive(Debug)]
+struct SecondaryIndex {
+    def: IndexDef,
+    fields: BTreeMap<Vec<u8>,BTreeSet<Vec<u8>>>, // encoded field -> keys holding it
+    keys: BTreeMap<Vec<u8>,Vec<u8>>, // key -> its encoded field
+}
+
+impl SecondaryIndex {
+    fn update(&mut self, key: &[u8], doc: Option<&Value>) {
+        if let Some(old) = self.keys.remove(key) {
+            let unused = match self.fields.get_mut(&old) {
+                Some(keys) => {
+                    keys.remove(key);
+                    keys.is_empty()
+                },
+                None => false,
+            };
+            if unused {
+                self.fields.remove(&old);
+            }
+        }
+
+        if let Some(field) = doc.and_then(|doc| self.def.extract(doc)) {
+            self.fields.entry(field.clone()).or_default().insert(key.to_vec());
+            self.keys.insert(key.to_vec(), field);
+        }
+    }
+}
+
+// the secondary indexes of a store, kept in memory and rebuilt when it's opened
+#[derive(Debug,Default)]
+pub struct Indexes {
+    indexes: BTreeMap<String,SecondaryIndex>,
+}
+
+impl Indexes {
+    pub fn new(defs: &[IndexDef]) -> Indexes {
+        let indexes = defs.iter()
+            .map(|def| (def.name.clone(), SecondaryIndex {
+                def: def.clone(),
+                fields: BTreeMap::new(),
+                keys: BTreeMap::new(),
+            }))
+            .collect();
+        Indexes { indexes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        for index in self.indexes.values_mut() {
+            index.fields.clear();
+            index.keys.clear();
+        }
+    }
+
+    // value is the key's new value, None if it's been removed. values that aren't json aren't indexed.
+    pub fn update(&mut self, key: &[u8], value: Option<&[u8]>) {
+        let doc = value.and_then(|v| serde_json::from_slice::<Value>(v).ok());
+        for index in self.indexes.values_mut() {
+            index.update(key, doc.as_ref());
+        }
+    }
+
+    // keys whose field lies in range, in order of the field and then the key
+    pub fn range<'a, R: RangeBounds<&'a Value>>(&self, name: &str, range: R) -> Result<Vec<Vec<u8>>> {
+        let index = self.indexes.get(name)
+            .ok_or_else(|| KvsErrorKind::NotFound(format!("index {}", name)))?;
+        let cursor = Cursor::new((encode_bound(range.start_bound())?, encode_bound(range.end_bound())?));
+        if cursor.is_empty() {
+            return Ok(vec![]);
+        }
+
+        Ok(index.fields.range(cursor.bounds())
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect())
+    }
+}
@@ -0,0 +1,225 @@
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::{Arc,Weak,Mutex,MutexGuard,RwLock};
+use std::time::Duration;
+
+use crate::result::*;
+use crate::command::{self as cmd,Command};
+use crate::blob::Blobs;
+use crate::kvdb::KvDb;
+use crate::logdb::Offset;
+use crate::merge::{self,MergeOperator};
+use crate::parts::Id;
+use crate::scan::{Scan,Cursor};
+use crate::worker::Worker;
+use crate::{KvStore,KvStoreParams,WriteBatch,OffsetIndex,PartitionsMap,Entry};
+
+// the parts of a store that reads need, shared between the writer and every handle.
+// the locks are only held to look something up or swap it, never across reading or writing the log,
+// so reads carry on while a write is being appended.
+pub(crate) struct Shared {
+    index: RwLock<Arc<OffsetIndex>>, // copied on write while a snapshot or scan holds it
+    kvdbs: RwLock<Arc<PartitionsMap>>, // likewise, so a scan can still read partitions compaction has replaced
+    blobs: Blobs,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+// the index and partitions as they were at one point
+pub(crate) struct View {
+    pub index: Arc<OffsetIndex>,
+    pub kvdbs: Arc<PartitionsMap>,
+}
+
+impl Shared {
+    pub fn new(kvdbs: PartitionsMap, blobs: Blobs, merge_operator: Option<Arc<dyn MergeOperator>>) -> Shared {
+        Shared {
+            index: RwLock::new(Arc::new(OffsetIndex::new())),
+            kvdbs: RwLock::new(Arc::new(kvdbs)),
+            blobs,
+            merge_operator,
+        }
+    }
+
+    // the index as it is now. later writes don't change it.
+    pub fn index(&self) -> Arc<OffsetIndex> {
+        self.index.read().unwrap().clone()
+    }
+
+    // every partition is added before the index refers to it, and removed after it stops,
+    // so holding the index lock while the partitions are taken means they match
+    pub fn view(&self) -> View {
+        let index = self.index.read().unwrap();
+        View {
+            index: index.clone(),
+            kvdbs: self.kvdbs.read().unwrap().clone(),
+        }
+    }
+
+    pub fn set_index(&self, index: OffsetIndex) {
+        *self.index.write().unwrap() = Arc::new(index);
+    }
+
+    // copying an index that a snapshot, scan or compaction holds only shares its nodes,
+    // and the write then copies the few it changes
+    pub fn update_index<T, F: FnOnce(&mut OffsetIndex) -> T>(&self, f: F) -> T {
+        let mut index = self.index.write().unwrap();
+        f(Arc::make_mut(&mut index))
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.read().unwrap().len()
+    }
+
+    // the entry for key, unless it's missing or has expired
+    pub fn entry(&self, key: &[u8]) -> Option<Entry> {
+        self.index.read().unwrap()
+            .get(key)
+            .filter(|e| !e.is_expired(cmd::now()))
+            .cloned()
+    }
+
+    pub fn part(&self, id: Id) -> Result<Arc<KvDb>> {
+        part(&self.kvdbs.read().unwrap(), id)
+    }
+
+    pub fn insert_part(&self, id: Id, kvdb: KvDb) {
+        Arc::make_mut(&mut self.kvdbs.write().unwrap()).insert(id, Arc::new(kvdb));
+    }
+
+    pub fn remove_part(&self, id: Id) {
+        Arc::make_mut(&mut self.kvdbs.write().unwrap()).remove(&id);
+    }
+
+    pub fn read_offset(&self, id: Id, offset: Offset) -> Result<Command> {
+        self.part(id)?.read_offset(offset)
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (entry, kvdbs) = {
+            let index = self.index.read().unwrap();
+            match index.get(key).filter(|e| !e.is_expired(cmd::now())) {
+                Some(entry) => (entry.clone(), self.kvdbs.read().unwrap().clone()),
+                None => return Ok(None),
+            }
+        };
+
+        match self.read_value(&kvdbs, key, &entry) {
+            // blobs aren't kept for readers, so one can be collected once the key has moved on from it
+            Err(_) if self.index.read().unwrap().get(key) != Some(&entry) => self.get_bytes(key),
+            result => result,
+        }
+    }
+
+    // the value the entry for key points at, with any merges applied. a batch record holds values for other keys too.
+    pub fn read_value(&self, kvdbs: &PartitionsMap, key: &[u8], entry: &Entry) -> Result<Option<Vec<u8>>> {
+        let mut records = vec![];
+        for e in entry.older.iter().chain(std::iter::once(entry)) {
+            if let Some(record) = part(kvdbs, e.part)?.read_offset(e.offset)?.find(key) {
+                records.push(self.blobs.resolve(record)?);
+            }
+        }
+        merge::fold(self.merge_operator.as_deref(), key, records, cmd::now())
+    }
+}
+
+fn part(kvdbs: &PartitionsMap, id: Id) -> Result<Arc<KvDb>> {
+    Ok(kvdbs.get(&id)
+        .cloned()
+        .ok_or(KvsErrorKind::InvalidPartition(id))?)
+}
+
+// writes tombstones for expired keys every interval, taking its turn with the other writers
+fn start_sweeper(interval: Duration, store: Weak<Mutex<KvStore>>) -> Worker<KvsError> {
+    Worker::start(interval, move || {
+        match store.upgrade() {
+            Some(store) => store.lock().unwrap().sweep_expired().map(|_| ()),
+            None => Ok(()),
+        }
+    })
+}
+
+// a handle on a store that can be cloned and used from many threads at once. reads go straight to
+// the shared index and partitions, and run in parallel with each other and with writes, which take
+// turns on the store behind a mutex.
+#[derive(Clone)]
+pub struct SharedKvStore {
+    writer: Arc<Mutex<KvStore>>,
+    shared: Arc<Shared>,
+    sweeper: Option<Arc<Worker<KvsError>>>, // started if the store has a sweep interval, and stopped with the last handle
+}
+
+impl SharedKvStore {
+    pub fn new(store: KvStore) -> SharedKvStore {
+        let interval = store.params.sweep_interval;
+        let shared = store.shared.clone();
+        let writer = Arc::new(Mutex::new(store));
+        SharedKvStore {
+            sweeper: interval.map(|interval| Arc::new(start_sweeper(interval, Arc::downgrade(&writer)))),
+            writer,
+            shared,
+        }
+    }
+
+    pub fn open(path: &Path) -> Result<SharedKvStore> {
+        Ok(SharedKvStore::new(KvStore::open(path)?))
+    }
+
+    pub fn open_with_params(path: &Path, params: KvStoreParams) -> Result<SharedKvStore> {
+        Ok(SharedKvStore::new(KvStore::open_with_params(path, params)?))
+    }
+
+    // the store itself, for anything that isn't a plain read or write. reads from other handles
+    // carry on while it's held.
+    pub fn lock(&self) -> MutexGuard<'_,KvStore> {
+        self.writer.lock().unwrap()
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?.map(into_string).transpose()
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.shared.get_bytes(key)
+    }
+
+    // key/value pairs with keys in range, in order. it goes over the keys as they were when it started.
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan<'_> {
+        self.resume(Cursor::new(range))
+    }
+
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Scan<'_> {
+        self.resume(Cursor::prefix(prefix))
+    }
+
+    pub fn resume(&self, cursor: Cursor) -> Scan<'_> {
+        Scan::new(&self.shared, self.shared.view(), cursor)
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.writer()?.set(key, value)
+    }
+
+    pub fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.writer()?.set_bytes(key, value)
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.writer()?.remove(key)
+    }
+
+    pub fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.writer()?.remove_bytes(key)
+    }
+
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.writer()?.write(batch)
+    }
+
+    // a failed sweep is reported by the next write
+    fn writer(&self) -> Result<MutexGuard<'_,KvStore>> {
+        if let Some(e) = self.sweeper.as_ref().and_then(|s| s.take_error()) {
+            return Err(e);
+        }
+        Ok(self.lock())
+    }
+}
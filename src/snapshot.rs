@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap,BTreeSet};
+use std::sync::{Arc,Mutex};
+
+use crate::result::*;
+use crate::command;
+use crate::blob::Blobs;
+use crate::codec::Codec;
+use crate::kvdb::KvDb;
+use crate::merge::{self,MergeOperator};
+use crate::parts::{Parts,Id};
+use crate::OffsetIndex;
+
+// keeps partition files that compaction has replaced on disk for as long as a snapshot still reads them
+#[derive(Clone,Debug)]
+pub struct Retention {
+    parts: Parts,
+    state: Arc<Mutex<RetentionState>>,
+}
+
+#[derive(Debug,Default)]
+struct RetentionState {
+    pins: BTreeMap<Id,usize>, // partition -> number of snapshots using it
+    retired: BTreeSet<Id>, // partitions to remove once they're no longer pinned
+}
+
+impl Retention {
+    pub fn new(parts: Parts) -> Retention {
+        Retention {
+            parts,
+            state: Arc::new(Mutex::new(RetentionState::default())),
+        }
+    }
+
+    pub fn pin(&self, ids: &[Id]) -> Pin {
+        let mut state = self.state.lock().unwrap();
+        for id in ids {
+            *state.pins.entry(*id).or_insert(0) += 1;
+        }
+
+        Pin {
+            retention: self.clone(),
+            ids: ids.to_vec(),
+        }
+    }
+
+    // whether any snapshot is open
+    pub fn is_pinned(&self) -> bool {
+        !self.state.lock().unwrap().pins.is_empty()
+    }
+
+    // removes a partition that's no longer live, or leaves it to the last snapshot using it
+    pub fn retire(&self, id: Id) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.pins.contains_key(&id) {
+            state.retired.insert(id);
+            Ok(())
+        } else {
+            self.parts.remove(id)
+        }
+    }
+
+    fn unpin(&self, ids: &[Id]) {
+        let mut state = self.state.lock().unwrap();
+        for id in ids {
+            let unused = match state.pins.get_mut(id) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                },
+                None => false,
+            };
+            if unused {
+                state.pins.remove(id);
+                if state.retired.remove(id) {
+                    // anything left behind is cleaned up as an orphan on the next open
+                    let _ = self.parts.remove(*id);
+                }
+            }
+        }
+    }
+}
+
+// the partitions a snapshot reads from
+pub struct Pin {
+    retention: Retention,
+    ids: Vec<Id>,
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        self.retention.unpin(&self.ids);
+    }
+}
+
+// a read-only view of the store as it was when the snapshot was taken
+pub struct Snapshot {
+    index: Arc<OffsetIndex>,
+    parts: Parts,
+    codec: Arc<dyn Codec>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    kvdbs: BTreeMap<Id,KvDb>, // opened as they're needed, separately from the store's
+    seq: u64,
+    _pin: Pin,
+}
+
+impl Snapshot {
+    pub(crate) fn new(index: Arc<OffsetIndex>, parts: Parts, codec: Arc<dyn Codec>, merge_operator: Option<Arc<dyn MergeOperator>>, seq: u64, pin: Pin) -> Snapshot {
+        Snapshot {
+            index,
+            parts,
+            codec,
+            merge_operator,
+            kvdbs: BTreeMap::new(),
+            seq,
+            _pin: pin,
+        }
+    }
+
+    // sequence number of the last write the snapshot includes
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?.map(into_string).transpose()
+    }
+
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // expiry is judged by the clock, so a key can still expire while the snapshot is open
+        let now = command::now();
+        let entry = match self.index.get(key) {
+            Some(entry) if !entry.is_expired(now) => entry.clone(),
+            _ => return Ok(None),
+        };
+
+        // blobs aren't pinned, but aren't collected while any snapshot is open
+        let blobs = Blobs::new(&self.parts.dir);
+        let mut records = vec![];
+        for e in entry.older.iter().chain(std::iter::once(&entry)) {
+            if let Some(record) = self.part_mut(e.part)?.read_offset(e.offset)?.find(key) {
+                records.push(blobs.resolve(record)?);
+            }
+        }
+        merge::fold(self.merge_operator.as_deref(), key, records, now)
+    }
+
+    fn part_mut(&mut self, id: Id) -> Result<&mut KvDb> {
+        if !self.kvdbs.contains_key(&id) {
+            let kvdb = KvDb::open(&self.parts, id, self.codec.clone())?;
+            self.kvdbs.insert(id, kvdb);
+        }
+        Ok(self.kvdbs.get_mut(&id).expect("error"))
+    }
+}
@@ -0,0 +1,188 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+
+use crate::result::*;
+use crate::blob::Blobs;
+use crate::codec::{self,Codec};
+use crate::command::Command;
+use crate::kvdb::{KvDb,Visitor};
+use crate::logdb::Offset;
+use crate::manifest::Manifest;
+use crate::parts::{Parts,Id};
+
+// a point in the log: the partition, and the offset in it of the next record to read.
+// saving it and opening a tail from it later carries on where an earlier one stopped.
+#[derive(Copy,Clone,PartialEq,Eq,PartialOrd,Ord,Hash,Debug,Serialize,Deserialize)]
+pub struct Position {
+    pub part: Id,
+    pub offset: Offset,
+}
+
+impl Position {
+    pub fn new(part: Id, offset: Offset) -> Position {
+        Position {
+            part,
+            offset,
+        }
+    }
+
+    // the beginning of the oldest live partition. partition ids start at 1.
+    pub fn start() -> Position {
+        Position::new(0, 0)
+    }
+
+    fn is_start(&self) -> bool {
+        self.part == 0
+    }
+}
+
+// a command read from the log
+#[derive(Clone,PartialEq,Debug)]
+pub struct Change {
+    pub at: Position, // where the record starts
+    pub next: Position, // where the record after it starts
+    pub command: Command,
+}
+
+struct Collector<'a> {
+    blobs: Blobs,
+    part: Id,
+    max: usize,
+    changes: &'a mut Vec<Change>,
+}
+
+impl <'a> Visitor for Collector<'a> {
+    fn command(&mut self, command: Command, pos: Offset, len: u64) -> Result<bool> {
+        // values moved out to blob files are read back, unless they've been collected since
+        let command = match self.blobs.resolve(command) {
+            Ok(command) => command,
+            Err(e) => match e.kind() {
+                KvsErrorKind::NotFound(_) => Err(KvsErrorKind::HistoryCompacted(self.part, pos))?,
+                _ => return Err(e),
+            },
+        };
+        self.changes.push(Change {
+            at: Position::new(self.part, pos),
+            next: Position::new(self.part, pos + len),
+            command,
+        });
+        Ok(self.changes.len() < self.max)
+    }
+}
+
+// reads the commands appended to a store's log after a position, following it from one partition
+// to the next as they're rotated. it only reads the files, so it can run alongside the store or in
+// another process.
+// compaction removes partitions once their live data has been copied out, and a tail that hasn't
+// finished with a partition by then gets HistoryCompacted. it has to start again from a fresh copy
+// of the store's state.
+pub struct Tail {
+    parts: Parts,
+    codec: Arc<dyn Codec>,
+    position: Position,
+}
+
+impl Tail {
+    pub fn open(path: &Path, position: Position) -> Result<Tail> {
+        let mut parts = Parts::new(path);
+        let name = match parts.read_format()? {
+            Some(format) => {
+                parts.unframed = format.unframed;
+                format.codec
+            },
+            None => codec::DEFAULT_CODEC.to_owned(),
+        };
+        let codec = codec::builtin(&name)
+            .ok_or_else(|| KvsErrorKind::Config(format!("unknown codec: {}", name)))?;
+        Ok(Tail::new(parts, codec, position))
+    }
+
+    // for stores written with a codec that isn't built in
+    pub fn open_with_codec(path: &Path, position: Position, codec: Arc<dyn Codec>) -> Result<Tail> {
+        let mut parts = Parts::new(path);
+        if let Some(format) = parts.read_format()? {
+            parts.unframed = format.unframed;
+        }
+        Ok(Tail::new(parts, codec, position))
+    }
+
+    pub(crate) fn new(parts: Parts, codec: Arc<dyn Codec>, position: Position) -> Tail {
+        Tail {
+            parts,
+            codec,
+            position,
+        }
+    }
+
+    // where the next poll starts from
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    // up to max commands appended since the position, oldest first. empty once it's caught up.
+    pub fn poll(&mut self, max: usize) -> Result<Vec<Change>> {
+        let live = Manifest::read(&self.parts.manifest_path())?
+            .map(|m| m.parts)
+            .unwrap_or_default();
+
+        let mut changes = vec![];
+        while changes.len() < max {
+            if self.position.is_start() {
+                match live.first() {
+                    Some(id) => self.position = Position::new(*id, 0),
+                    None => break,
+                }
+            }
+
+            let i = live.iter()
+                .position(|id| *id == self.position.part)
+                .ok_or_else(|| self.compacted())?;
+            let last = i + 1 == live.len();
+
+            let read = changes.len();
+            self.read(&mut changes, max, last)?;
+            if changes.len() > read {
+                self.position = changes[changes.len() - 1].next;
+            }
+
+            if changes.len() < max && !last {
+                // sealed partitions don't change, so anything short of max means this one is done
+                self.position = Position::new(live[i + 1], 0);
+            } else {
+                break;
+            }
+        }
+
+        Ok(changes)
+    }
+
+    fn read(&self, changes: &mut Vec<Change>, max: usize, last: bool) -> Result<()> {
+        let Position { part, offset } = self.position;
+        let file = match self.parts.open(part) {
+            Ok(file) => file,
+            // removed since the manifest was read
+            Err(_) if !self.parts.path_for_id(part).exists() => Err(self.compacted())?,
+            Err(e) => return Err(e),
+        };
+        if offset > self.parts.size(part)? {
+            Err(self.compacted())?;
+        }
+
+        let kvdb = KvDb::with_framing(part, file, self.codec.clone(), self.parts.is_framed(part))?;
+        let collector = Collector { blobs: Blobs::new(&self.parts.dir), part, max, changes };
+        match kvdb.visit_from(offset, collector) {
+            Ok(_) => Ok(()),
+            Err(e) => match e.kind() {
+                // a record the store is still in the middle of writing. it's read on a later poll.
+                KvsErrorKind::Corruption(_, pos) if last && kvdb.is_incomplete(*pos)? => Ok(()),
+                _ => Err(e),
+            },
+        }
+    }
+
+    fn compacted(&self) -> KvsErrorKind {
+        KvsErrorKind::HistoryCompacted(self.position.part, self.position.offset)
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+
+use crate::result::*;
+use crate::batch::WriteBatch;
+use crate::KvStore;
+
+// an optimistic transaction. reads go to the store and are remembered, writes are buffered
+// until commit, which fails with a conflict if anything that was read has changed since.
+#[derive(Clone,Debug)]
+pub struct Transaction {
+    start: u64, // store sequence number when the transaction began
+    reads: BTreeMap<Vec<u8>,Option<u64>>, // key -> sequence number of the value that was read, None if it was missing
+    writes: WriteBatch,
+}
+
+impl Transaction {
+    pub(crate) fn new(start: u64) -> Transaction {
+        Transaction {
+            start,
+            reads: BTreeMap::new(),
+            writes: WriteBatch::new(),
+        }
+    }
+
+    pub fn get(&mut self, store: &KvStore, key: String) -> Result<Option<String>> {
+        self.get_bytes(store, key.as_bytes())?.map(into_string).transpose()
+    }
+
+    // sees the transaction's own writes before anything in the store
+    pub fn get_bytes(&mut self, store: &KvStore, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(op) = self.writes.op(key) {
+            return Ok(op.clone());
+        }
+
+        let value = store.get_bytes(key)?;
+        self.reads.entry(key.to_vec()).or_insert_with(|| store.version(key));
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.set(key, value);
+    }
+
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.set_bytes(key, value);
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.writes.remove(key);
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) {
+        self.writes.remove_bytes(key);
+    }
+
+    pub fn commit(self, store: &mut KvStore) -> Result<()> {
+        store.commit(self)
+    }
+
+    // fails with the first key that was changed after the transaction began or after it was read
+    pub(crate) fn validate(&self, store: &KvStore) -> Result<()> {
+        for (key, read) in self.reads.iter() {
+            let changed = store.version(key) != *read || matches!(read, Some(seq) if *seq > self.start);
+            if changed {
+                Err(KvsErrorKind::Conflict(String::from_utf8_lossy(key).into_owned()))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn into_writes(self) -> WriteBatch {
+        self.writes
+    }
+}
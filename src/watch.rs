@@ -0,0 +1,128 @@
+use std::fmt;
+use std::sync::mpsc::{self,Receiver,Sender};
+
+use crate::command::Command;
+
+// a change to a watched key, sent once it's been written
+#[derive(Clone,PartialEq,Debug)]
+pub struct Event {
+    pub seq: u64, // sequence number of the write
+    pub key: Vec<u8>,
+    pub kind: EventKind,
+}
+
+#[derive(Clone,PartialEq,Debug)]
+pub enum EventKind {
+    Set(Vec<u8>), // the new value
+    Remove,
+    Expire, // removed by the sweeper because it expired
+    Merge(Vec<u8>), // the operand
+}
+
+// which keys a watcher is told about
+#[derive(Clone,PartialEq,Debug)]
+pub enum Watch {
+    Key(Vec<u8>),
+    Prefix(Vec<u8>),
+}
+
+impl Watch {
+    pub fn matches(&self, key: &[u8]) -> bool {
+        match self {
+            Watch::Key(k) => &k[..] == key,
+            Watch::Prefix(prefix) => key.starts_with(prefix),
+        }
+    }
+}
+
+pub type WatchId = u64;
+
+enum Sink {
+    Channel(Sender<Event>),
+    Callback(Box<dyn FnMut(&Event) + Send>),
+}
+
+impl Sink {
+    // false once nobody is listening any more
+    fn send(&mut self, event: &Event) -> bool {
+        match self {
+            Sink::Channel(tx) => tx.send(event.clone()).is_ok(),
+            Sink::Callback(f) => {
+                f(event);
+                true
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Watchers {
+    next_id: WatchId,
+    watchers: Vec<(WatchId,Watch,Sink)>,
+}
+
+impl fmt::Debug for Watchers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.watchers.iter().map(|(id, watch, _)| (id, watch)))
+            .finish()
+    }
+}
+
+impl Watchers {
+    pub fn new() -> Watchers {
+        Watchers::default()
+    }
+
+    // events are queued on the channel. the watcher goes away when the receiver is dropped.
+    pub fn channel(&mut self, watch: Watch) -> Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.add(watch, Sink::Channel(tx));
+        rx
+    }
+
+    // the callback runs on the writing thread, before the write returns
+    pub fn callback(&mut self, watch: Watch, f: Box<dyn FnMut(&Event) + Send>) -> WatchId {
+        self.add(watch, Sink::Callback(f))
+    }
+
+    pub fn remove(&mut self, id: WatchId) {
+        self.watchers.retain(|(i, _, _)| *i != id);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchers.is_empty()
+    }
+
+    fn add(&mut self, watch: Watch, sink: Sink) -> WatchId {
+        self.next_id += 1;
+        self.watchers.push((self.next_id, watch, sink));
+        self.next_id
+    }
+
+    // tells watchers about every operation in a record that's just been written
+    pub fn notify(&mut self, command: Command, seq: u64, expired: bool) {
+        for (op, _) in command.ops(0) {
+            let (key, kind) = match op {
+                Command::Set { key, value, expires: _ } => (key, EventKind::Set(value)),
+                Command::Remove { key } if expired => (key, EventKind::Expire),
+                Command::Remove { key } => (key, EventKind::Remove),
+                Command::Merge { key, operand } => (key, EventKind::Merge(operand)),
+                // watchers are told about sets before their values are moved out to blobs
+                Command::Blob { key: _, id: _, expires: _ } => continue,
+                Command::Batch { ops: _ } => continue,
+            };
+            let event = Event { seq, key, kind };
+
+            let mut i = 0;
+            while i < self.watchers.len() {
+                let (_, ref watch, ref mut sink) = self.watchers[i];
+                if watch.matches(&event.key) && !sink.send(&event) {
+                    self.watchers.remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::{self,Sender,RecvTimeoutError};
+use std::thread::{self,JoinHandle};
+use std::time::Duration;
+
+// runs a task from a background thread every interval until it's dropped.
+// an error from the task is kept for whoever calls take_error next.
+pub struct Worker<E> {
+    error: Arc<Mutex<Option<E>>>,
+    stop: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl <E: Send + 'static> Worker<E> {
+    pub fn start<F>(interval: Duration, mut task: F) -> Worker<E>
+        where F: FnMut() -> Result<(),E> + Send + 'static
+    {
+        let error = Arc::new(Mutex::new(None));
+        let (stop, stopped) = mpsc::channel();
+
+        let thread_error = error.clone();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = task() {
+                    *thread_error.lock().unwrap() = Some(e);
+                }
+            }
+        });
+
+        Worker {
+            error,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    // the last error hit by the task, if it hasn't been reported yet
+    pub fn take_error(&self) -> Option<E> {
+        self.error.lock().unwrap().take()
+    }
+}
+
+impl <E> Drop for Worker<E> {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
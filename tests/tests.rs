@@ -1,8 +1,21 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::codec::BinaryCodec;
+use kvs::durability::Durability;
+use kvs::merge::{AddI64, Append};
+use kvs::secondary::IndexDef;
+use kvs::watch::{EventKind, Watch};
+use kvs::rotation::MaxRecords;
+use kvs::command::Command as KvCommand;
+use kvs::{CasResult, KeyspaceBatch, Keyspaces, KvStore, KvStoreParams, KvsErrorKind, Position, Result, Tail, WriteBatch};
 use predicates::ord::eq;
+use serde_json::json;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -191,7 +204,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -230,7 +243,7 @@ fn get_non_existent_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -289,7 +302,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -299,3 +312,1372 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// A damaged record should be reported as corruption, naming the partition and offset
+#[test]
+fn corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.kvs");
+    let mut data = std::fs::read(&path).expect("unable to read partition");
+    data[10] ^= 0xff;
+    std::fs::write(&path, data).expect("unable to write partition");
+
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => match e.kind() {
+            KvsErrorKind::Corruption(1, 0) => {},
+            other => panic!("unexpected error: {}", other),
+        },
+        Ok(_) => panic!("corruption not detected"),
+    }
+
+    Ok(())
+}
+
+// A store written before records were framed, with a json record per line, is still read.
+// New records go to a framed partition, and compaction moves the old ones over.
+#[test]
+fn unframed_partitions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let part1 = "{\"op\":\"Set\",\"key\":\"key1\",\"value\":\"value1\"}\n{\"op\":\"Set\",\"key\":\"key2\",\"value\":\"value2\"}\n";
+    let part2 = "{\"op\":\"Remove\",\"key\":\"key1\"}\n{\"op\":\"Set\",\"key\":\"key3\",\"value\":\"value3\"}\n";
+    fs::write(temp_dir.path().join("1.kvs"), part1).expect("unable to write partition");
+    fs::write(temp_dir.path().join("2.kvs"), part2).expect("unable to write partition");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.metrics.recovery.is_none());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    assert_eq!(fs::read_to_string(temp_dir.path().join("1.kvs")).expect("unable to read partition"), part1);
+    assert_eq!(fs::read_to_string(temp_dir.path().join("2.kvs")).expect("unable to read partition"), part2);
+    let framed = fs::read(temp_dir.path().join("3.kvs")).expect("unable to read partition");
+    assert_eq!(u32::from_le_bytes([framed[0], framed[1], framed[2], framed[3]]) as usize, framed.len() - 8);
+
+    let mut tail = Tail::open(temp_dir.path(), Position::start())?;
+    let keys: Vec<Vec<u8>> = tail.poll(10)?.into_iter().filter_map(|c| c.command.key().map(|k| k.to_vec())).collect();
+    assert_eq!(keys, vec![b"key1".to_vec(), b"key2".to_vec(), b"key1".to_vec(), b"key3".to_vec(), b"key4".to_vec()]);
+
+    let mut params = KvStoreParams::new();
+    params.compact_part_garbage_ratio = 0.0;
+    let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+    store.compact()?;
+    drop(store);
+    assert!(!temp_dir.path().join("1.kvs").exists());
+    assert!(!temp_dir.path().join("2.kvs").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A store keeps the codec it was created with, whatever the params say when reopening
+#[test]
+fn binary_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.codec = Arc::new(BinaryCodec);
+    let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A store from before codecs were configurable is json, and stays json whatever the params say
+#[test]
+fn baseline_store_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let part1 = "{\"op\":\"Set\",\"key\":\"a\",\"value\":\"1\"}\n{\"op\":\"Set\",\"key\":\"b\",\"value\":\"2\"}\n";
+    fs::write(temp_dir.path().join("1.kvs"), part1).expect("unable to write partition");
+
+    let mut params = KvStoreParams::new();
+    params.codec = Arc::new(BinaryCodec);
+    let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+    store.set("c".to_owned(), "3".to_owned())?;
+    drop(store);
+
+    let format = fs::read_to_string(temp_dir.path().join("FORMAT")).expect("missing format marker");
+    assert_eq!(format.lines().next(), Some("json"));
+    let framed = fs::read(temp_dir.path().join("2.kvs")).expect("unable to read partition");
+    assert_eq!(&framed[8..9], b"{");
+
+    // the marker is replaced whole, so an interrupted write only leaves a temporary file behind
+    fs::write(temp_dir.path().join("FORMAT.tmp"), "").expect("unable to write format marker");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+
+    Ok(())
+}
+
+// A partial record left at the end of the log by a crash is cut off when the store is opened
+#[test]
+fn torn_write_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.metrics.recovery.is_none());
+    drop(store);
+
+    let path = temp_dir.path().join("1.kvs");
+    let len = std::fs::metadata(&path).expect("unable to stat partition").len();
+    let mut f = OpenOptions::new().append(true).open(&path).expect("unable to open partition");
+    f.write_all(&[42, 0, 0, 0, 1, 2]).expect("unable to write partition");
+    drop(f);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let report = store.metrics.recovery.clone().expect("no recovery reported");
+    assert_eq!(report.part, 1);
+    assert_eq!(report.offset, len);
+    assert_eq!(report.bytes, 6);
+    assert_eq!(std::fs::read(&report.saved_to).expect("missing side file"), vec![42, 0, 0, 0, 1, 2]);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.metrics.recovery.is_none());
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A torn first write to a new partition is cut off like any other, but damage to a first record
+// that's followed by more isn't, and the partition is left alone
+#[test]
+fn torn_first_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.kvs");
+    let data = std::fs::read(&path).expect("unable to read partition");
+    std::fs::write(&path, &data[..data.len() - 3]).expect("unable to write partition");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let report = store.metrics.recovery.clone().expect("no recovery reported");
+    assert_eq!((report.part, report.offset, report.bytes), (1, 0, data.len() as u64 - 3));
+    assert_eq!(std::fs::metadata(&path).expect("unable to stat partition").len(), 0);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // the first write after a rotation
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.rotate()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let path = temp_dir.path().join("2.kvs");
+    let data = std::fs::read(&path).expect("unable to read partition");
+    std::fs::write(&path, &data[..10]).expect("unable to write partition");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let report = store.metrics.recovery.clone().expect("no recovery reported");
+    assert_eq!((report.part, report.offset), (2, 0));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut data = std::fs::read(&path).expect("unable to read partition");
+    data[8] ^= 0xff; // the first byte of the first record, after its header
+    std::fs::write(&path, &data).expect("unable to write partition");
+
+    match KvStore::open(temp_dir.path()) {
+        Err(e) => match e.kind() {
+            KvsErrorKind::Corruption(2, 0) => {},
+            other => panic!("unexpected error: {}", other),
+        },
+        Ok(_) => panic!("corruption not detected"),
+    }
+    assert_eq!(std::fs::read(&path).expect("unable to read partition"), data);
+
+    Ok(())
+}
+
+// Sealed partitions get a hint file, which load uses instead of scanning the partition
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    // the current partition's hint is built from the writes to it, including those before it was reopened
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".to_owned(), "value2".to_owned()).remove("key1".to_owned());
+    store.write(batch)?;
+    store.rotate()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let hint_path = temp_dir.path().join("1.hint");
+    let hint = std::fs::read(&hint_path).expect("missing hint");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metrics.entries, 4);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // a damaged hint falls back to scanning the partition, and is rewritten
+    std::fs::write(&hint_path, b"garbage").expect("unable to write hint");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(std::fs::read(&hint_path).expect("missing hint"), hint);
+
+    Ok(())
+}
+
+// Partition files missing from the manifest, e.g. left by a crashed compaction, are ignored and removed
+#[test]
+fn manifest_ignores_stray_partitions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut other = KvStore::open(other_dir.path())?;
+    other.set("key1".to_owned(), "stale".to_owned())?;
+    drop(other);
+    let stray = temp_dir.path().join("9.kvs");
+    std::fs::copy(other_dir.path().join("1.kvs"), &stray).expect("unable to copy partition");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!stray.exists());
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Writes made while a background compaction is running win over the compacted copies
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.compact_garbage_threshold = 100;
+    let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    assert!(store.start_compaction()?);
+    assert!(store.is_compacting());
+    assert!(!store.start_compaction()?);
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.remove("key99".to_owned())?;
+    store.wait_compaction()?;
+    assert!(!store.is_compacting());
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..99 {
+            let expected = if key_id < 50 { "new" } else { "9" };
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(expected.to_owned()));
+        }
+        assert_eq!(store.get("key99".to_owned())?, None);
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+
+    // a cancelled compaction leaves the store untouched
+    store.start_compaction()?;
+    store.cancel_compaction()?;
+    assert!(!store.is_compacting());
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// Compaction only rewrites partitions that are mostly garbage, and keeps the tombstones
+// that still shadow values in the partitions it leaves alone
+#[test]
+fn incremental_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "clean".to_owned())?;
+    }
+    store.rotate()?;
+    store.remove("key0".to_owned())?;
+    for iter in 0..10 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    store.rotate()?;
+
+    store.compact()?;
+    assert!(temp_dir.path().join("1.kvs").exists());
+    assert!(!temp_dir.path().join("2.kvs").exists());
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("clean".to_owned()));
+    assert_eq!(store.get("hot".to_owned())?, Some("9".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("clean".to_owned()));
+    assert_eq!(store.get("hot".to_owned())?, Some("9".to_owned()));
+
+    Ok(())
+}
+
+// Dead bytes are tracked per partition as keys are overwritten and removed, and rebuilt on load
+#[test]
+fn garbage_accounting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.metrics.dead_bytes(), 0);
+    let total = store.metrics.total_bytes();
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.metrics.dead_bytes(), total / 2);
+    store.rotate()?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.metrics.parts[&1].dead_bytes(), total);
+    assert_eq!(store.metrics.parts[&2].dead_bytes(), 0);
+
+    let expected = store.metrics.parts.clone();
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metrics.parts, expected);
+
+    Ok(())
+}
+
+// The current partition is sealed as soon as it crosses the size limit or a rotation policy fires
+#[test]
+fn rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.max_part_size = 1000;
+    let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let sizes: Vec<u64> = store.metrics.parts.values().map(|p| p.total_bytes).collect();
+    assert!(sizes.len() > 2);
+    for size in sizes[..sizes.len() - 1].iter() {
+        assert!(*size > 1000 && *size < 1100);
+    }
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.rotation.push(Arc::new(MaxRecords(10)));
+    let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+    for key_id in 0..25 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let records: Vec<u64> = store.metrics.parts.values().map(|p| p.records).collect();
+    assert_eq!(records, vec![10, 10, 5]);
+
+    drop(store);
+    let store = KvStore::open_with_params(temp_dir.path(), params)?;
+    for key_id in 0..25 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Keys and values can be arbitrary bytes with either codec
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    for codec in ["json", "binary"].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut params = KvStoreParams::new();
+        params.codec = kvs::codec::builtin(codec).unwrap();
+        let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+        store.set_bytes(&[0xff, 0, 1], &[0, 159, 146, 150])?;
+        store.set_bytes(b"text", &[0xc3, 0x28])?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get_bytes(&[0xff, 0, 1])?, Some(vec![0, 159, 146, 150]));
+        assert_eq!(store.get_bytes(b"key1")?, Some(b"value1".to_vec()));
+        match store.get("text".to_owned()) {
+            Err(e) => match e.kind() {
+                KvsErrorKind::Utf8Error(0) => {},
+                other => panic!("unexpected error: {}", other),
+            },
+            Ok(v) => panic!("expected utf-8 error, got {:?}", v),
+        }
+        store.remove_bytes(&[0xff, 0, 1])?;
+        assert_eq!(store.get_bytes(&[0xff, 0, 1])?, None);
+    }
+
+    Ok(())
+}
+
+// Every durability mode keeps acknowledged writes across a reopen
+#[test]
+fn durability_modes() -> Result<()> {
+    let modes = vec![
+        Durability::Always,
+        Durability::GroupCommit { window: Duration::from_millis(10), max_bytes: 100 },
+        Durability::Periodic(Duration::from_millis(5)),
+        Durability::Os,
+    ];
+    for durability in modes {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut params = KvStoreParams::new();
+        params.durability = durability;
+        params.max_part_size = 200;
+        let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0".to_owned())?;
+        store.flush()?;
+        store.sync()?;
+        drop(store);
+
+        let store = KvStore::open_with_params(temp_dir.path(), params)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..20 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        }
+    }
+
+    Ok(())
+}
+
+// A batch is logged as one record, so a torn batch is dropped whole on open
+#[test]
+fn write_batch() -> Result<()> {
+    for codec in ["json", "binary"].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut params = KvStoreParams::new();
+        params.codec = kvs::codec::builtin(codec).unwrap();
+        let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let mut batch = WriteBatch::new();
+        batch.set("key2".to_owned(), "value2".to_owned())
+            .set("key3".to_owned(), "value3".to_owned())
+            .remove("key1".to_owned())
+            .remove("missing".to_owned());
+        store.write(batch)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        // a second batch whose record gets cut short
+        let path = temp_dir.path().join("1.kvs");
+        let len = std::fs::metadata(&path).expect("unable to stat partition").len();
+        let mut batch = WriteBatch::new();
+        batch.set("key2".to_owned(), "changed".to_owned())
+            .set("key4".to_owned(), "value4".to_owned());
+        store.write(batch)?;
+        drop(store);
+        OpenOptions::new().write(true).open(&path).expect("unable to open partition")
+            .set_len(len + 10).expect("unable to truncate partition");
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert!(store.metrics.recovery.is_some());
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, None);
+
+        // live operations survive compaction of a partially overwritten batch
+        store.set("key2".to_owned(), "value2b".to_owned())?;
+        store.compact()?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2b".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// A transaction commits only if nothing it read was changed in the meantime
+#[test]
+fn optimistic_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("balance".to_owned(), "100".to_owned())?;
+
+    let mut txn = store.transaction();
+    let balance: i64 = txn.get(&store, "balance".to_owned())?.unwrap().parse().unwrap();
+    txn.set("balance".to_owned(), (balance - 30).to_string());
+    txn.set("log".to_owned(), "withdrew 30".to_owned());
+    assert_eq!(txn.get(&store, "balance".to_owned())?, Some("70".to_owned()));
+    assert_eq!(store.get("balance".to_owned())?, Some("100".to_owned()));
+    txn.commit(&mut store)?;
+    assert_eq!(store.get("balance".to_owned())?, Some("70".to_owned()));
+    assert_eq!(store.get("log".to_owned())?, Some("withdrew 30".to_owned()));
+
+    // a concurrent write to a key that was read
+    let mut txn = store.transaction();
+    txn.get(&store, "balance".to_owned())?;
+    txn.set("balance".to_owned(), "0".to_owned());
+    store.set("balance".to_owned(), "50".to_owned())?;
+    match txn.commit(&mut store) {
+        Err(e) => match e.kind() {
+            KvsErrorKind::Conflict(key) => assert_eq!(key, "balance"),
+            other => panic!("unexpected error: {}", other),
+        },
+        Ok(()) => panic!("expected a conflict"),
+    }
+    assert_eq!(store.get("balance".to_owned())?, Some("50".to_owned()));
+
+    // a key that was missing when read and created since
+    let mut txn = store.transaction();
+    assert_eq!(txn.get(&store, "limit".to_owned())?, None);
+    store.set("limit".to_owned(), "10".to_owned())?;
+    txn.set("balance".to_owned(), "1".to_owned());
+    assert!(txn.commit(&mut store).is_err());
+
+    // writes to keys the transaction didn't read don't conflict
+    let mut txn = store.transaction();
+    txn.get(&store, "balance".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    txn.remove("log".to_owned());
+    txn.commit(&mut store)?;
+    assert_eq!(store.get("log".to_owned())?, None);
+
+    Ok(())
+}
+
+// A snapshot keeps seeing the store as it was, through writes and compaction
+#[test]
+fn snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    store.remove("key9".to_owned())?;
+
+    let mut snapshot = store.snapshot();
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+
+    assert_eq!(snapshot.len(), 9);
+    assert_eq!(snapshot.get("key0".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key5".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key9".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, Some("new".to_owned()));
+
+    // the compacted partition stays on disk until the snapshot is done with it
+    assert!(temp_dir.path().join("1.kvs").exists());
+    drop(snapshot);
+    assert!(!temp_dir.path().join("1.kvs").exists());
+
+    Ok(())
+}
+
+// Holding a snapshot doesn't make writes copy the whole index
+#[test]
+fn writes_with_snapshot_held() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    for key_id in 0..100_000 {
+        batch.set(format!("key{}", key_id), "value".to_owned());
+    }
+    store.write(batch)?;
+
+    let start = Instant::now();
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    let unheld = start.elapsed();
+
+    // each snapshot sees a different version of the index, and every write comes after one is taken
+    let mut snapshots = vec![];
+    let start = Instant::now();
+    for key_id in 0..100 {
+        snapshots.push(store.snapshot());
+        store.set(format!("key{}", key_id), "newer".to_owned())?;
+    }
+    let held = start.elapsed();
+    // copying the index for each write would take many times longer than the writes themselves
+    assert!(held < unheld * 5 + Duration::from_millis(200), "{:?} with snapshots, {:?} without", held, unheld);
+    assert_eq!(snapshots[0].get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(snapshots[99].get("key98".to_owned())?, Some("newer".to_owned()));
+    assert_eq!(snapshots[99].get("key99".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+// Range and prefix scans return pairs in key order from either end, and can be resumed
+#[test]
+fn scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for tenant in ["acme", "globex", "initech"].iter() {
+        for user_id in 0..5 {
+            store.set(format!("{}/user{}", tenant, user_id), format!("{} {}", tenant, user_id))?;
+        }
+    }
+    store.remove("globex/user2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("globex/user9".to_owned(), "globex 9".to_owned());
+    store.write(batch)?;
+
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<String> {
+        pairs.into_iter().map(|(k, _)| String::from_utf8(k).unwrap()).collect()
+    };
+
+    let globex = store.scan_prefix(b"globex/").collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(globex.clone()), vec!["globex/user0", "globex/user1", "globex/user3", "globex/user4", "globex/user9"]);
+    assert_eq!(globex[4].1, b"globex 9".to_vec());
+
+    let reversed = store.scan(b"acme/user3".to_vec()..b"globex/user1".to_vec()).rev().collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(reversed), vec!["globex/user0", "acme/user4", "acme/user3"]);
+
+    // page through everything two at a time
+    let mut pages = vec![];
+    let mut cursor = kvs::Cursor::new(..);
+    loop {
+        let mut scan = store.resume(cursor);
+        let page = scan.by_ref().take(2).collect::<Result<Vec<_>>>()?;
+        cursor = scan.cursor();
+        if page.is_empty() {
+            break;
+        }
+        pages.push(page.len());
+    }
+    assert_eq!(pages, vec![2, 2, 2, 2, 2, 2, 2, 1]);
+
+    assert_eq!(store.scan(b"z".to_vec()..b"a".to_vec()).count(), 0);
+
+    Ok(())
+}
+
+// Keys set with a ttl disappear once it passes, and stay gone across reopens and compaction
+#[test]
+fn ttl_expiry() -> Result<()> {
+    for codec in ["json", "binary"].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut params = KvStoreParams::new();
+        params.codec = kvs::codec::builtin(codec).unwrap();
+        let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+        store.set("session".to_owned(), "old".to_owned())?;
+        store.rotate()?;
+        store.set_with_ttl("session".to_owned(), "short".to_owned(), Duration::from_secs(1))?;
+        store.set_with_ttl("token".to_owned(), "long".to_owned(), Duration::from_secs(3600))?;
+        store.set("plain".to_owned(), "value".to_owned())?;
+        assert_eq!(store.get("session".to_owned())?, Some("short".to_owned()));
+
+        // sleeps last at least as long as they're asked to, so this only relies on the writes above taking under a second
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(store.get("session".to_owned())?, None);
+        assert_eq!(store.scan(..).count(), 2);
+        assert!(store.remove("session".to_owned()).is_err());
+
+        // the expired value still hides the older one after a reopen, with or without hints
+        store.rotate()?;
+        drop(store);
+        let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+        assert_eq!(store.get("session".to_owned())?, None);
+        assert_eq!(store.get("token".to_owned())?, Some("long".to_owned()));
+        store.compact()?;
+        drop(store);
+        let store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+        assert_eq!(store.get("session".to_owned())?, None);
+        assert_eq!(store.get("token".to_owned())?, Some("long".to_owned()));
+        assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A ttl too long to add to the current time is refused, and one that's merely huge never expires
+#[test]
+fn ttl_overflow() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    match store.set_with_ttl("key".to_owned(), "value".to_owned(), Duration::from_secs(u64::MAX)) {
+        Err(e) => match e.kind() {
+            KvsErrorKind::Config(_) => {},
+            other => panic!("unexpected error: {}", other),
+        },
+        Ok(_) => panic!("ttl overflow not detected"),
+    }
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    store.set_with_ttl("key".to_owned(), "value".to_owned(), Duration::from_secs(1 << 60))?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// The sweeper writes tombstones for keys that have expired
+#[test]
+fn ttl_sweep() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.sweep_interval = Some(Duration::from_millis(100));
+    let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+    for key_id in 0..5 {
+        store.set_with_ttl(format!("key{}", key_id), "value".to_owned(), Duration::from_secs(1))?;
+    }
+    assert_eq!(store.sweep_expired()?, 0);
+
+    // every key has expired by now, and the next write is the first sweep to find them
+    std::thread::sleep(Duration::from_millis(1100));
+    store.set("other".to_owned(), "value".to_owned())?;
+    assert_eq!(store.sweep_expired()?, 0);
+    assert_eq!(store.scan(..).count(), 1);
+    assert_eq!(store.metrics.entries, 11);
+
+    Ok(())
+}
+
+// A shared store sweeps from a background thread, so keys expire without any further writes
+#[test]
+fn ttl_background_sweep() -> Result<()> {
+    use kvs::SharedKvStore;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.sweep_interval = Some(Duration::from_millis(20));
+    let store = SharedKvStore::open_with_params(temp_dir.path(), params)?;
+    let events = store.lock().watch(b"session");
+    store.lock().set_with_ttl("session".to_owned(), "value".to_owned(), Duration::from_millis(100))?;
+    assert_eq!(events.recv_timeout(Duration::from_secs(10)).expect("no set event").kind, EventKind::Set(b"value".to_vec()));
+
+    let event = events.recv_timeout(Duration::from_secs(10)).expect("no expire event");
+    assert_eq!(event.kind, EventKind::Expire);
+    assert_eq!(event.key, b"session".to_vec());
+    assert_eq!(store.get("session".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metrics.entries, 2);
+
+    Ok(())
+}
+
+// Conditional writes only happen when the key holds the expected value
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.set_if_absent("stock".to_owned(), "10".to_owned())?, CasResult::Written);
+    assert_eq!(store.set_if_absent("stock".to_owned(), "20".to_owned())?, CasResult::Mismatch(Some("10".to_owned())));
+
+    let swapped = store.compare_and_swap("stock".to_owned(), Some("10".to_owned()), Some("9".to_owned()))?;
+    assert!(swapped.is_written());
+    let stale = store.compare_and_swap("stock".to_owned(), Some("10".to_owned()), Some("8".to_owned()))?;
+    assert_eq!(stale, CasResult::Mismatch(Some("9".to_owned())));
+    assert_eq!(store.get("stock".to_owned())?, Some("9".to_owned()));
+
+    assert_eq!(store.remove_if_equals("stock".to_owned(), "10".to_owned())?, CasResult::Mismatch(Some("9".to_owned())));
+    assert_eq!(store.remove_if_equals("stock".to_owned(), "9".to_owned())?, CasResult::Written);
+    assert_eq!(store.get("stock".to_owned())?, None);
+    assert_eq!(store.remove_if_equals("stock".to_owned(), "9".to_owned())?, CasResult::Mismatch(None));
+
+    assert_eq!(store.compare_and_swap_bytes(&[1, 2], None, Some(&[0xff]))?, CasResult::Written);
+    assert_eq!(store.compare_and_swap_bytes(&[1, 2], Some(&[0xff]), None)?, CasResult::Written);
+    assert_eq!(store.get_bytes(&[1, 2])?, None);
+
+    Ok(())
+}
+
+// Merge operands are folded into the value on read and collapsed by compaction
+#[test]
+fn merge_operators() -> Result<()> {
+    for codec in ["json", "binary"].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut params = KvStoreParams::new();
+        params.codec = kvs::codec::builtin(codec).unwrap();
+        params.merge_operator = Some(Arc::new(AddI64));
+        let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+
+        store.set("hits".to_owned(), "10".to_owned())?;
+        for _ in 0..5 {
+            store.merge("hits".to_owned(), "1".to_owned())?;
+        }
+        store.merge("misses".to_owned(), "-3".to_owned())?;
+        assert_eq!(store.get("hits".to_owned())?, Some("15".to_owned()));
+        assert_eq!(store.get("misses".to_owned())?, Some("-3".to_owned()));
+
+        // chains spanning partitions, read back from hints
+        store.rotate()?;
+        store.merge("hits".to_owned(), "5".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+        assert_eq!(store.get("hits".to_owned())?, Some("20".to_owned()));
+        let mut snapshot = store.snapshot();
+
+        store.compact()?;
+        store.merge("hits".to_owned(), "100".to_owned())?;
+        assert_eq!(store.get("hits".to_owned())?, Some("120".to_owned()));
+        assert_eq!(snapshot.get("hits".to_owned())?, Some("20".to_owned()));
+        drop(snapshot);
+        store.compact()?;
+        drop(store);
+
+        // collapsed into a plain value, unlike a lone operand
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("hits".to_owned())?, Some("120".to_owned()));
+        assert!(store.get("misses".to_owned()).is_err());
+        drop(store);
+
+        let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+        assert_eq!(store.get("hits".to_owned())?, Some("120".to_owned()));
+        assert_eq!(store.get("misses".to_owned())?, Some("-3".to_owned()));
+        store.remove("hits".to_owned())?;
+        store.merge("hits".to_owned(), "1".to_owned())?;
+        assert_eq!(store.get("hits".to_owned())?, Some("1".to_owned()));
+
+        // merged keys can't be read without the operator
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert!(store.get("hits".to_owned()).is_err());
+        assert!(store.merge("hits".to_owned(), "1".to_owned()).is_err());
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.merge_operator = Some(Arc::new(Append::with_separator(b",")));
+    let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+    store.merge("tags".to_owned(), "red".to_owned())?;
+    store.merge("tags".to_owned(), "green".to_owned())?;
+    store.merge("tags".to_owned(), "blue".to_owned())?;
+    assert_eq!(store.get("tags".to_owned())?, Some("red,green,blue".to_owned()));
+
+    Ok(())
+}
+
+// Watchers hear about writes to their keys once they've been logged
+#[test]
+fn watchers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let key_events = store.watch(b"cache/a");
+    let prefix_events = store.watch_prefix(b"cache/");
+    let seen = Arc::new(Mutex::new(vec![]));
+    let callback_seen = seen.clone();
+    let id = store.watch_with(Watch::Key(b"other".to_vec()), move |e| callback_seen.lock().unwrap().push(e.kind.clone()));
+
+    store.set("cache/a".to_owned(), "1".to_owned())?;
+    store.set("cache/b".to_owned(), "2".to_owned())?;
+    store.set("other".to_owned(), "3".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.remove("cache/a".to_owned()).set("cache/c".to_owned(), "4".to_owned());
+    store.write(batch)?;
+    store.set_with_ttl("cache/d".to_owned(), "5".to_owned(), Duration::from_millis(10))?;
+    std::thread::sleep(Duration::from_millis(20));
+    store.sweep_expired()?;
+    store.unwatch(id);
+    store.remove("other".to_owned())?;
+
+    let events: Vec<_> = key_events.try_iter().map(|e| e.kind).collect();
+    assert_eq!(events, vec![EventKind::Set(b"1".to_vec()), EventKind::Remove]);
+
+    let events: Vec<_> = prefix_events.try_iter().map(|e| (String::from_utf8(e.key).unwrap(), e.kind)).collect();
+    assert_eq!(events, vec![
+        ("cache/a".to_owned(), EventKind::Set(b"1".to_vec())),
+        ("cache/b".to_owned(), EventKind::Set(b"2".to_vec())),
+        ("cache/a".to_owned(), EventKind::Remove),
+        ("cache/c".to_owned(), EventKind::Set(b"4".to_vec())),
+        ("cache/d".to_owned(), EventKind::Set(b"5".to_vec())),
+        ("cache/d".to_owned(), EventKind::Expire),
+    ]);
+
+    assert_eq!(*seen.lock().unwrap(), vec![EventKind::Set(b"3".to_vec())]);
+
+    // dropped receivers just stop getting events
+    drop(key_events);
+    store.set("cache/a".to_owned(), "6".to_owned())?;
+    assert_eq!(prefix_events.try_iter().count(), 1);
+
+    Ok(())
+}
+
+// A tail reads back every command written after its position, across rotations,
+// and says so when compaction has removed what it was reading
+#[test]
+fn tail_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.rotation.push(Arc::new(MaxRecords(5)));
+    let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    let mut tail = store.tail(store.position()?);
+    for key_id in 3..12 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let keys = |changes: Vec<kvs::Change>| -> Vec<String> {
+        changes.into_iter().map(|c| String::from_utf8(c.command.key().unwrap().to_vec()).unwrap()).collect()
+    };
+    assert_eq!(keys(tail.poll(3)?), vec!["key3", "key4", "key5"]);
+    let rest = tail.poll(100)?;
+    assert_eq!(rest.last().map(|c| c.command.clone()), Some(KvCommand::Remove { key: b"key0".to_vec() }));
+    assert_eq!(keys(rest).len(), 7);
+    assert!(tail.poll(100)?.is_empty());
+    assert_eq!(tail.position(), store.position()?);
+
+    // a tail reading the files from a saved position sees the same
+    let mut from_start = Tail::open(temp_dir.path(), Position::start())?;
+    assert_eq!(keys(from_start.poll(100)?).len(), 13);
+    assert_eq!(from_start.position(), store.position()?);
+
+    let stale = Position::new(1, 0);
+    for key_id in 1..3 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert_eq!(tail.poll(100)?.len(), 2);
+    store.compact()?;
+    assert!(!temp_dir.path().join("1.kvs").exists());
+    // compaction's copies aren't new changes
+    store.set("key12".to_owned(), "new".to_owned())?;
+    assert_eq!(keys(tail.poll(100)?), vec!["key12"]);
+    match Tail::open(temp_dir.path(), stale)?.poll(100) {
+        Err(e) => match e.kind() {
+            KvsErrorKind::HistoryCompacted(1, 0) => {},
+            e => panic!("unexpected error {}", e),
+        },
+        Ok(_) => panic!("expected compacted history"),
+    }
+
+    // a record that's still being written is left for a later poll, but a damaged one is reported
+    let position = store.position()?;
+    let path = temp_dir.path().join(format!("{}.kvs", position.part));
+    let mut f = OpenOptions::new().append(true).open(&path).expect("unable to open partition");
+    f.write_all(&[42, 0, 0, 0, 1, 2]).expect("unable to write partition");
+    assert!(tail.poll(100)?.is_empty());
+    assert_eq!(tail.position(), position);
+
+    f.set_len(position.offset).expect("unable to truncate partition");
+    let mut record = kvs::frames::encode(b"damaged");
+    record[4] ^= 0xff;
+    f.write_all(&record).expect("unable to write partition");
+    match tail.poll(100) {
+        Err(e) => match e.kind() {
+            KvsErrorKind::Corruption(part, offset) => assert_eq!(Position::new(*part, *offset), position),
+            e => panic!("unexpected error {}", e),
+        },
+        Ok(_) => panic!("expected corruption"),
+    }
+
+    Ok(())
+}
+
+// Keyspaces in one directory are independent, and batches across them are applied together
+#[test]
+fn keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = BTreeMap::new();
+    let mut sessions = KvStoreParams::new();
+    sessions.rotation.push(Arc::new(MaxRecords(2)));
+    params.insert("sessions".to_owned(), sessions);
+    let mut keyspaces = Keyspaces::open_with_params(temp_dir.path(), params.clone())?;
+    keyspaces.open_keyspace("users", KvStoreParams::new())?;
+    assert!(keyspaces.open_keyspace("../users", KvStoreParams::new()).is_err());
+    assert_eq!(keyspaces.names(), vec!["sessions", "users"]);
+
+    keyspaces.keyspace("users")?.set("alice".to_owned(), "admin".to_owned())?;
+    let mut batch = KeyspaceBatch::new();
+    batch.keyspace("users").set("bob".to_owned(), "guest".to_owned());
+    batch.keyspace("sessions")
+        .set("s1".to_owned(), "alice".to_owned())
+        .set("s2".to_owned(), "bob".to_owned())
+        .set("s3".to_owned(), "bob".to_owned());
+    keyspaces.write(batch)?;
+
+    // a batch naming a keyspace that doesn't exist isn't applied anywhere
+    let mut batch = KeyspaceBatch::new();
+    batch.keyspace("users").remove("alice".to_owned());
+    batch.keyspace("config").set("a".to_owned(), "b".to_owned());
+    assert!(keyspaces.write(batch).is_err());
+
+    assert_eq!(keyspaces.keyspace("users")?.get("alice".to_owned())?, Some("admin".to_owned()));
+    assert_eq!(keyspaces.keyspace("users")?.get("s1".to_owned())?, None);
+    drop(keyspaces);
+
+    // directories that aren't keyspaces are left alone
+    for name in ["lost+found", ".git", "backup"].iter() {
+        fs::create_dir(temp_dir.path().join(name)).expect("unable to create directory");
+    }
+    let mut keyspaces = Keyspaces::open_with_params(temp_dir.path(), params)?;
+    assert_eq!(keyspaces.names(), vec!["sessions", "users"]);
+    assert_eq!(keyspaces.keyspace("users")?.get("bob".to_owned())?, Some("guest".to_owned()));
+    assert_eq!(keyspaces.keyspace("sessions")?.get("s3".to_owned())?, Some("bob".to_owned()));
+    drop(keyspaces);
+
+    // a journalled batch that didn't get applied before a crash is applied on the next open
+    let pending = concat!(r#"{"batches":[["users",{"op":"Batch","ops":[{"op":"Remove","key":"bob"}]}],"#,
+        r#"["sessions",{"op":"Batch","ops":[{"op":"Remove","key":"s2"},{"op":"Remove","key":"s3"}]}]]}"#);
+    let mut journal = OpenOptions::new().write(true).open(temp_dir.path().join("JOURNAL")).unwrap();
+    journal.write_all(&kvs::frames::encode(pending.as_bytes())).unwrap();
+    drop(journal);
+
+    let mut keyspaces = Keyspaces::open(temp_dir.path())?;
+    assert_eq!(keyspaces.keyspace("users")?.get("bob".to_owned())?, None);
+    assert_eq!(keyspaces.keyspace("sessions")?.get("s3".to_owned())?, None);
+    assert_eq!(keyspaces.keyspace("sessions")?.get("s1".to_owned())?, Some("alice".to_owned()));
+
+    // a batch left in the journal by a failed write is finished before a keyspace is handed out,
+    // so a later write to it directly isn't undone
+    let pending = r#"{"batches":[["users",{"op":"Batch","ops":[{"op":"Set","key":"bob","value":"guest"}]}]]}"#;
+    let mut journal = OpenOptions::new().write(true).open(temp_dir.path().join("JOURNAL")).unwrap();
+    journal.write_all(&kvs::frames::encode(pending.as_bytes())).unwrap();
+    drop(journal);
+    keyspaces.keyspace("users")?.set("bob".to_owned(), "admin".to_owned())?;
+    keyspaces.write(KeyspaceBatch::new())?;
+    assert_eq!(keyspaces.keyspace("users")?.get("bob".to_owned())?, Some("admin".to_owned()));
+
+    keyspaces.remove_keyspace("sessions")?;
+    assert_eq!(keyspaces.names(), vec!["users"]);
+    assert!(!temp_dir.path().join("sessions").exists());
+
+    Ok(())
+}
+
+// Secondary indexes on json fields follow writes and are rebuilt when the store is opened
+#[test]
+fn secondary_indexes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.indexes.push(IndexDef::new("email", "$.email")?);
+    params.indexes.push(IndexDef::new("age", "$.profile.age")?);
+    assert!(IndexDef::new("bad", "email").is_err());
+    assert!(IndexDef::new("bad", "$.tags[x]").is_err());
+
+    let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+    store.set("u1".to_owned(), r#"{"email": "a@example.com", "profile": {"age": 31}}"#.to_owned())?;
+    store.set("u2".to_owned(), r#"{"email": "b@example.com", "profile": {"age": 25}}"#.to_owned())?;
+    store.set("u3".to_owned(), r#"{"email": "a@example.com", "profile": {"age": -4.5}}"#.to_owned())?;
+    store.set("u4".to_owned(), "not json".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("u5".to_owned(), r#"{"email": "c@example.com"}"#.to_owned()).remove("u3".to_owned());
+    store.write(batch)?;
+    store.set("u2".to_owned(), r#"{"email": "b@example.com", "profile": {"age": 26}}"#.to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        let keys = |keys: Vec<Vec<u8>>| -> Vec<String> {
+            keys.into_iter().map(|k| String::from_utf8(k).unwrap()).collect()
+        };
+        assert_eq!(keys(store.find_by("email", &json!("a@example.com"))?), vec!["u1"]);
+        assert_eq!(keys(store.find_by("age", &json!(26))?), vec!["u2"]);
+        assert_eq!(keys(store.find_by("age", &json!(25))?), Vec::<String>::new());
+        assert_eq!(keys(store.find_range("email", &json!("b")..)?), vec!["u2", "u5"]);
+        assert_eq!(keys(store.find_range("age", ..=&json!(100))?), vec!["u2", "u1"]);
+        assert_eq!(keys(store.find_range("age", &json!(30)..&json!(20))?), Vec::<String>::new());
+        assert!(store.find_by("name", &json!("a")).is_err());
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_params(temp_dir.path(), params)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Large values live in blob files of their own, which compaction leaves alone and collect_blobs cleans up
+#[test]
+fn blob_values() -> Result<()> {
+    for codec in ["json", "binary"].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut params = KvStoreParams::new();
+        params.codec = kvs::codec::builtin(codec).unwrap();
+        params.blob_threshold = Some(100);
+        params.compact_part_garbage_ratio = 0.1;
+        let large = |n: usize| -> String { format!("{}", n).repeat(500) };
+        let blob_count = |dir: &std::path::Path| WalkDir::new(dir).into_iter()
+            .filter(|e| matches!(e.as_ref().unwrap().path().extension(), Some(ext) if ext == "blob"))
+            .count();
+
+        let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+        let events = store.watch(b"doc0");
+        let mut tail = store.tail(Position::start());
+        for n in 0..3 {
+            store.set(format!("doc{}", n), large(n))?;
+        }
+        store.set("small".to_owned(), "tiny".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.set("doc1".to_owned(), large(7)).set("doc3".to_owned(), large(3));
+        store.write(batch)?;
+        store.remove("doc2".to_owned())?;
+        assert_eq!(blob_count(temp_dir.path()), 5);
+        assert!(fs::metadata(temp_dir.path().join("1.kvs")).unwrap().len() < 1000);
+
+        assert_eq!(store.get("doc0".to_owned())?, Some(large(0)));
+        assert_eq!(store.get("doc1".to_owned())?, Some(large(7)));
+        assert_eq!(events.try_iter().next().map(|e| e.kind), Some(EventKind::Set(large(0).into_bytes())));
+        assert_eq!(tail.poll(1)?[0].command, KvCommand::Set { key: b"doc0".to_vec(), value: large(0).into_bytes(), expires: None });
+
+        // the old doc1 and doc2 go. the newest blob is always kept.
+        let snapshot = store.snapshot();
+        assert_eq!(store.collect_blobs()?, 0);
+        drop(snapshot);
+        assert_eq!(store.collect_blobs()?, 2);
+        assert_eq!(blob_count(temp_dir.path()), 3);
+        store.rotate()?;
+        store.compact()?;
+        assert!(!temp_dir.path().join("1.kvs").exists());
+        drop(store);
+
+        let mut store = KvStore::open_with_params(temp_dir.path(), params)?;
+        assert_eq!(store.get("doc0".to_owned())?, Some(large(0)));
+        assert_eq!(store.get("doc1".to_owned())?, Some(large(7)));
+        assert_eq!(store.get("doc2".to_owned())?, None);
+        assert_eq!(store.get("doc3".to_owned())?, Some(large(3)));
+        assert_eq!(store.get("small".to_owned())?, Some("tiny".to_owned()));
+        store.set("doc4".to_owned(), large(4))?;
+        assert_eq!(blob_count(temp_dir.path()), 4);
+
+        // a tail that reaches a collected blob can't carry on
+        match tail.poll(100) {
+            Err(e) => match e.kind() {
+                KvsErrorKind::HistoryCompacted(1, _) => {},
+                e => panic!("unexpected error {}", e),
+            },
+            Ok(_) => panic!("expected compacted history"),
+        }
+    }
+
+    Ok(())
+}
+
+// Reads only need a shared reference to the store, and still notice damaged records
+#[test]
+fn positional_reads() -> Result<()> {
+    use std::io::{Seek, SeekFrom};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    // a scan and gets running side by side over the same store
+    let shared = &store;
+    let mut scan = shared.scan_prefix(b"key");
+    assert_eq!(scan.next().transpose()?, Some((b"key0".to_vec(), b"value0".to_vec())));
+    assert_eq!(shared.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(scan.next_back().transpose()?, Some((b"key4".to_vec(), b"value4".to_vec())));
+    assert_eq!(scan.count(), 3);
+
+    let damage = |offset: u64, bytes: &[u8]| {
+        let mut f = OpenOptions::new().write(true).open(temp_dir.path().join("1.kvs")).unwrap();
+        f.seek(SeekFrom::Start(offset)).unwrap();
+        f.write_all(bytes).unwrap();
+    };
+    let (_, offset) = store.get_offset(b"key1")?.unwrap();
+    damage(offset + 10, b"\xff");
+    let (_, huge) = store.get_offset(b"key2")?.unwrap();
+    damage(huge, &u32::MAX.to_le_bytes());
+    for (key, at) in [("key1", offset), ("key2", huge)].iter() {
+        match store.get(key.to_string()) {
+            Err(e) => match e.kind() {
+                KvsErrorKind::Corruption(1, o) if o == at => {},
+                other => panic!("unexpected error: {}", other),
+            },
+            Ok(_) => panic!("corruption not detected"),
+        }
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    Ok(())
+}
+
+// Clones of a shared handle can be used from many threads at once. Reads see every batch either
+// whole or not at all, and the store reopens with what the handles wrote.
+#[test]
+fn shared_store_handle() -> Result<()> {
+    use kvs::SharedKvStore;
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SharedKvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    // readers keep going while the writer overwrites every key and compacts the old values away
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..=20 {
+                let mut batch = WriteBatch::new();
+                for key_id in 0..20 {
+                    batch.set(format!("key{}", key_id), round.to_string());
+                }
+                store.write(batch)?;
+                if round % 5 == 0 {
+                    store.lock().compact()?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4).map(|_| {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..200 {
+                let round: u32 = store.get("key7".to_owned())?.expect("key missing").parse().unwrap();
+                assert!(round <= 20);
+                // every key comes from the same batch, as the scan sees the index as it was when it started
+                let values = store.scan_prefix(b"key").map(|r| r.map(|(_, v)| v)).collect::<Result<Vec<_>>>()?;
+                assert_eq!(values.len(), 20);
+                assert!(values.iter().all(|v| *v == values[0]));
+            }
+            Ok(())
+        })
+    }).collect();
+
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(store.get("key19".to_owned())?, Some("20".to_owned()));
+
+    store.remove("key0".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("20".to_owned()));
+
+    Ok(())
+}
+
+// A reader on another thread keeps getting values while writes land and a compaction moves the old ones
+#[test]
+fn reads_during_writes_and_compaction() -> Result<()> {
+    use kvs::SharedKvStore;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.max_part_size = 16 * 1024;
+    params.compact_garbage_threshold = u32::MAX;
+    let store = SharedKvStore::open_with_params(temp_dir.path(), params)?;
+    let value = |round: u32| format!("{:08}{}", round, "x".repeat(500));
+    for round in 0..5 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value(round))?;
+        }
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let store = store.clone();
+        let done = done.clone();
+        thread::spawn(move || -> Result<u32> {
+            let mut seen = vec![0; 100];
+            let mut reads = 0;
+            while !done.load(Ordering::SeqCst) {
+                for (key_id, last) in seen.iter_mut().enumerate() {
+                    let value = store.get(format!("key{}", key_id))?.expect("key missing");
+                    let round: u32 = value[..8].parse().unwrap();
+                    assert!(round >= *last, "key{} went back from {} to {}", key_id, last, round);
+                    *last = round;
+                    reads += 1;
+                }
+            }
+            Ok(reads)
+        })
+    };
+
+    // each compaction runs in the background while the next rounds are written
+    let mut round = 5;
+    let mut overlapped = 0;
+    for _ in 0..3 {
+        assert!(store.lock().start_compaction()?);
+        while store.lock().is_compacting() {
+            for key_id in 0..100 {
+                store.set(format!("key{}", key_id), value(round))?;
+            }
+            round += 1;
+            overlapped += 1;
+            store.lock().poll_compaction()?;
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+
+    assert!(reader.join().unwrap()? > 0);
+    assert!(overlapped > 0);
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(round - 1)));
+    }
+
+    Ok(())
+}
+
+// Rotation and a compaction running in the background take partition ids from the same counter
+#[test]
+fn rotation_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut params = KvStoreParams::new();
+    params.max_part_size = 300;
+    params.compact_dead_ratio = Some(0.3);
+    params.compact_part_garbage_ratio = 0.1;
+    let mut store = KvStore::open_with_params(temp_dir.path(), params.clone())?;
+    for iter in 0..300 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open_with_params(temp_dir.path(), params)?;
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("299".to_owned()));
+    }
+
+    Ok(())
+}